@@ -1,6 +1,7 @@
 
 set -e
-cargo build --release --target x86_64-unknown-linux-musl
+# headless, the server boxes have no OpenGL
+cargo build --release --no-default-features --target x86_64-unknown-linux-musl
 ls -l target/x86_64-unknown-linux-musl/release/gupier
 strip -s target/x86_64-unknown-linux-musl/release/gupier
 ls -l target/x86_64-unknown-linux-musl/release/gupier
@@ -0,0 +1,103 @@
+use crate::com::Channel;
+use crate::connection::{self, Connection, ConnectionError, JoinConfig, LobbyEvent};
+use crate::gameplay::GameplayBot;
+use crate::server::Packet;
+use crate::util;
+use std::net::SocketAddr;
+
+#[derive(Clone, Debug)]
+pub struct BotConfig {
+    // inputs sent per second
+    pub tick_rate: u64,
+    pub join: JoinConfig,
+}
+
+impl Default for BotConfig {
+    fn default() -> Self {
+        BotConfig {
+            tick_rate: 60,
+            join: JoinConfig::default(),
+        }
+    }
+}
+
+// One bot: its own socket, so the server sees a separate connection,
+// ready for every match as soon as it is in the lobby.
+struct BotRunner<B> {
+    name: String,
+    conn: Connection,
+    gameplay: B,
+    tick_rate: u64,
+}
+
+impl<B> BotRunner<B>
+where B: GameplayBot {
+    fn new(name: String, ch: Channel, addr: SocketAddr, gameplay: B, config: &BotConfig) -> BotRunner<B> {
+        let request = config.join.request(gameplay.gameplay_id(), name.clone(), util::rand_color(), None);
+        BotRunner {
+            name,
+            conn: Connection::join(ch, addr, request, config.join.clone()),
+            gameplay,
+            tick_rate: config.tick_rate,
+        }
+    }
+
+    fn on_event(&mut self, event: LobbyEvent) -> Result<(), ConnectionError> {
+        match event {
+            LobbyEvent::Joined(_) => self.conn.send(Packet::Ready)?,
+            LobbyEvent::GameStarted => self.gameplay.init(self.conn.ids(), self.conn.addr()),
+            LobbyEvent::GameOver(_) => {
+                // straight into the rematch
+                self.gameplay.reset();
+                self.conn.send(Packet::Ready)?;
+            },
+            LobbyEvent::Gameplay(data) => self.gameplay.on_packet(self.conn.channel(), &data),
+            _ => {},
+        }
+        Ok(())
+    }
+
+    fn run(&mut self) -> Result<(), ConnectionError> {
+        let interval_ms = 1000 / self.tick_rate.max(1);
+        loop {
+            for event in self.conn.update()? {
+                self.on_event(event)?;
+            }
+            if self.conn.is_playing() {
+                self.gameplay.update(self.conn.channel());
+            }
+            util::sleep(interval_ms);
+        }
+    }
+}
+
+// Runs `n` bots against the server at `address` until all of them give up.
+pub fn start<B, F>(make: F, address: String, n: usize, config: BotConfig)
+where B: GameplayBot + Send + 'static, F: Fn() -> B {
+    let addr = match connection::resolve(&address) {
+        Ok(addr) => addr,
+        Err(e) => {
+            println!("[b] {}", e);
+            return
+        },
+    };
+    println!("[b] starting {} bots against {}", n, addr);
+
+    let threads: Vec<_> = (0..n).filter_map(|i| {
+        let ch = match Channel::spawn(None) {
+            Ok(ch) => ch.conditioned(&config.join.link),
+            Err(e) => {
+                println!("[b] bot-{}: {}", i + 1, e);
+                return None
+            },
+        };
+        let mut runner = BotRunner::new(format!("bot-{}", i + 1), ch, addr, make(), &config);
+        Some(std::thread::spawn(move || {
+            if let Err(e) = runner.run() {
+                println!("[b] {}: {}", runner.name, e);
+            }
+        }))
+    }).collect();
+
+    threads.into_iter().for_each(|t| { t.join().ok(); });
+}
@@ -1,142 +1,522 @@
-use crate::com::Channel;
+use crate::com::{self, Channel, ComError};
+use crate::connection::{self, Connection, ConnectionError, JoinConfig, LobbyEvent};
 use kiss3d::window::Window;
 use kiss3d::light::Light;
-use na::geometry::Point3;
-use std::net::{SocketAddr, ToSocketAddrs};
-use kiss3d::camera::FirstPerson;
+use kiss3d::conrod::{self, widget, widget_ids, Colorable, Labelable, Positionable, Sizeable, Widget};
+use na::geometry::{Point2, Point3};
+use kiss3d::text::Font;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use crate::split_camera::SplitCamera;
 use crate::gameplay::*;
-use crate::server::Packet;
+use crate::server::{Packet, Player, RejectReason, MAX_NAME_LEN};
 use crate::util;
 
-#[derive(Clone)]
-pub enum ClientState {
-    Menu,
-    Joining,
-    Joined,
-    Playing,
-}
+// menu address of the server running in this process, if any
+pub const LOCAL_HOST: &str = "local";
 
-#[derive(Clone)]
-struct Game<G> {
-    player_id: usize,
-    ch: Channel,
-    addr: SocketAddr,
-    gameplay: G
+#[derive(Clone, Debug, Default)]
+pub struct ClientConfig {
+    // reclaim the player of the last session saved for this server
+    pub rejoin: bool,
+    pub join: JoinConfig,
 }
 
-impl<G> Game<G> {
-    fn send_ro<T: serde::Serialize>(&mut self, data: T) {
-        self.ch.send_ro(self.addr, data);
+widget_ids! {
+    pub struct Ids {
+        canvas,
+        title,
+        address,
+        name,
+        red,
+        green,
+        blue,
+        connect,
+        roster,
+        results,
+        ready,
+        start,
+        leave,
+        status,
     }
 }
 
+// What the user picked in the menu and what the lobby told us
+struct Menu {
+    address: String,
+    name: String,
+    color: (f32, f32, f32),
+    status: String,
+    roster: Vec<Player>,
+    // scores of the last match, if any
+    results: String,
+    ready: bool,
+    // we can start the match without waiting for everybody to be ready
+    host: bool,
+}
+
+enum MenuAction {
+    Connect,
+    Ready(bool),
+    StartMatch,
+    Leave,
+}
+
 
 #[allow(dead_code)]
 struct Client<G> {
-    state: ClientState,
     window: Window,
-    game: Option<Game<G>>,
-    cam: FirstPerson,
+    ids: Ids,
+    menu: Menu,
+    net: Channel,
+    // in-process link to a server of ours, see LOCAL_HOST
+    local: Option<Channel>,
+    // the lobby we are in, over one of the two channels above
+    conn: Option<Connection>,
+    gameplay: G,
+    cam: SplitCamera,
+    config: ClientConfig,
 }
 
 
 impl<G> Client<G>
 where G: GameplayClient  {
-    fn new() -> Client<G> {
+    fn new(gameplay: G, address: String, config: ClientConfig, local: Option<Channel>) -> Result<Client<G>, ComError> {
         let mut window = Window::new("Kiss3d: cube");
         window.set_light(Light::StickToCamera);
+        let ids = Ids::new(window.conrod_ui_mut().widget_id_generator());
         // let cam = kiss3d::camera::FirstPerson::new(Point3::new(10.0, 10.0, 10.0), Point3::new(0.0, 0.0, 0.0));
-        let cam = kiss3d::camera::FirstPerson::new_with_frustrum(
-            -80.0,
-            0.2,
-            1000.0,
-            Point3::new(10.0, 10.0, 10.0),
-            Point3::new(0.0, 0.0, 0.0),
-        );
-        Client {
-            state: ClientState::Menu,
+        let cam = SplitCamera::new(-80.0, 0.2, 1000.0);
+        let net = Channel::spawn(None)?.conditioned(&config.join.link);
+        Ok(Client {
             window,
+            ids,
+            menu: Menu {
+                address,
+                name: std::env::var("USER").unwrap_or_else(|_| "player".to_string()),
+                color: util::rand_color(),
+                status: String::new(),
+                roster: vec![],
+                results: String::new(),
+                ready: false,
+                host: false,
+            },
+            net,
+            local,
+            conn: None,
+            gameplay,
             cam,
-            game: None,
+            config,
+        })
+    }
+
+    // `rejoin`: try the session saved for the server, if any
+    fn connect(&mut self, rejoin: bool) -> Result<(), ConnectionError> {
+        let host = self.menu.address.trim().to_string();
+        let (ch, addr) = match &self.local {
+            Some(local) if host == LOCAL_HOST => (local.clone(), com::loopback_addrs().0),
+            _ => (self.net.clone(), connection::resolve(&host)?),
+        };
+        let session = if rejoin { load_session(addr) } else { None };
+        if rejoin && session.is_none() {
+            println!("[c] no saved session for {}, joining lobby", addr);
         }
+        let request = self.config.join.request(self.gameplay.gameplay_id(), self.menu.name.clone(), self.menu.color, session);
+        self.conn = Some(Connection::join(ch, addr, request, self.config.join.clone()));
+        self.menu.status = format!("joining {}", addr);
+        Ok(())
     }
-    fn connect(&mut self, host: String, gameplay: G) {
-        self.game = Some(Game {
-            player_id: 0,
-            ch: Channel::spawn(None),
-            addr: host.to_socket_addrs().expect("Invalid host").next().expect("Cannot resolve hostname"),
-            gameplay,
-        })
+
+    fn send(&mut self, packet: Packet) {
+        let result = match &mut self.conn {
+            Some(conn) => conn.send(packet),
+            None => return,
+        };
+        if let Err(e) = result {
+            self.back_to_menu(e);
+        }
     }
-    fn join(&mut self) {
-        self.state = ClientState::Joining;
-        let game = self.game.as_mut().expect("game not ready ASD7YH");
 
-        println!("[c] joining lobby");
-        game.send_ro(Packet::Join(util::rand_color()));
+    fn poll(&mut self) {
+        let events = match &mut self.conn {
+            Some(conn) => conn.update(),
+            None => return,
+        };
+        match events {
+            Ok(events) => events.into_iter().for_each(|event| self.on_event(event)),
+            Err(ConnectionError::Rejected(RejectReason::UnknownSession)) => self.join_afresh(),
+            Err(e) => self.back_to_menu(e),
+        }
+    }
 
-        loop {
-            match game.ch.recv() {
-                Some((_, Packet::Joined(id))) => {
-                    println!("[c] joined {}", id);
-                    game.player_id = id;
-                    return
-                },
-                _ => {},
-            }
+    // The server no longer knows our session (e.g. it restarted): forget it
+    // too, or every later join would send it again.
+    fn join_afresh(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            println!("[c] {}: {}, joining as a new player", conn.addr(), RejectReason::UnknownSession);
+            forget_session(conn.addr());
+            conn.leave();
+        }
+        if let Err(e) = self.connect(false) {
+            self.back_to_menu(e);
         }
     }
 
-    fn wait_start(&mut self) {
-        self.state = ClientState::Joined;
-        let game = self.game.as_mut().expect("game not ready ASD7YH");
+    fn on_event(&mut self, event: LobbyEvent) {
+        let conn = match &mut self.conn {
+            Some(conn) => conn,
+            // an earlier event sent us back to the menu
+            None => return,
+        };
+        match event {
+            LobbyEvent::Joined(token) => {
+                save_session(conn.addr(), token);
+                self.menu.status = "waiting for the game to start".to_string();
+            },
+            LobbyEvent::Roster(roster) => {
+                let mine = conn.ids();
+                self.menu.host = roster.iter().any(|pl| pl.host && mine.contains(&pl.id));
+                self.menu.roster = roster;
+            },
+            LobbyEvent::Countdown(left) => {
+                self.menu.status = format!("match starts in {}", left);
+            },
+            LobbyEvent::CountdownAborted => {
+                self.menu.status = "waiting for the game to start".to_string();
+            },
+            LobbyEvent::GameStarted => {
+                println!("[c] game started");
+                self.cam.split(conn.ids().len());
+                self.gameplay.init(conn.ids(), conn.addr());
+                self.menu.status.clear();
+                self.menu.results.clear();
+            },
+            LobbyEvent::GameOver(results) => {
+                println!("[c] game over");
+                self.gameplay.reset(&mut self.window);
+                self.cam.split(1);
+                self.menu.results = self.format_results(&results);
+                self.menu.ready = false;
+                self.menu.status = "waiting for the rematch".to_string();
+            },
+            LobbyEvent::Gameplay(data) => {
+                self.gameplay.on_packet(conn.channel(), &data, &mut self.window);
+            },
+        }
+    }
 
-        println!("[c] wait game start");
-        loop {
-            match game.ch.recv() {
-                Some((_, Packet::GameStarted)) => {
-                    return
-                },
-                _ => {},
-            }
+    fn format_results(&self, results: &[(usize, i32)]) -> String {
+        let lines = results.iter().enumerate().map(|(i, (id, score))| {
+            let name = self.menu.roster.iter()
+                .find(|pl| pl.id == *id)
+                .map(|pl| pl.name.as_str())
+                .unwrap_or("(gone)");
+            format!("{}. {} #{} - {}", i+1, name, id, score)
+        });
+        std::iter::once("last match:".to_string())
+            .chain(lines)
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    fn my_ids(&self) -> Vec<usize> {
+        self.conn.as_ref().map(|conn| conn.ids().to_vec()).unwrap_or_default()
+    }
+
+    fn is_playing(&self) -> bool {
+        self.conn.as_ref().map_or(false, |conn| conn.is_playing())
+    }
+
+    fn back_to_menu(&mut self, err: ConnectionError) {
+        println!("[c] {}", err);
+        self.leave();
+        self.menu.status = err.to_string();
+    }
+
+    fn leave(&mut self) {
+        if self.is_playing() {
+            self.gameplay.reset(&mut self.window);
+            self.cam.split(1);
+        }
+        if let Some(conn) = self.conn.take() {
+            conn.leave();
         }
+        self.menu.roster.clear();
+        self.menu.results.clear();
+        self.menu.ready = false;
+        self.menu.host = false;
+        self.menu.status.clear();
     }
 
-    fn run(&mut self) {
-        println!("[c] game started");
-        self.state = ClientState::Playing;
-        let game = self.game.as_mut().expect("game not ready ASD7YH");
+    fn draw_menu(&mut self) {
+        let action = {
+            let mut ui = self.window.conrod_ui_mut().set_widgets();
+            gui(&mut ui, &self.ids, &mut self.menu, self.conn.as_ref())
+        };
+        match action {
+            Some(MenuAction::Connect) => {
+                if let Err(e) = self.connect(self.config.rejoin) {
+                    self.menu.status = e.to_string();
+                }
+            },
+            Some(MenuAction::Ready(ready)) => {
+                self.menu.ready = ready;
+                self.send(if ready { Packet::Ready } else { Packet::Unready });
+            },
+            Some(MenuAction::StartMatch) => {
+                self.send(Packet::StartMatch);
+            },
+            Some(MenuAction::Leave) => {
+                self.leave();
+                self.menu.status = "left the lobby".to_string();
+            },
+            None => {},
+        }
+    }
+
+    fn update_playing(&mut self) {
         let window = &mut self.window;
+        let ch = match &mut self.conn {
+            Some(conn) => conn.channel(),
+            None => return,
+        };
+        let gameplay = &mut self.gameplay;
+
+        window.events().iter().for_each(|e| {
+            gameplay.on_event(ch, &e.value, window);
+        });
 
-        game.gameplay.init(game.player_id, game.addr);
+        gameplay.update(ch, window, self.cam.cams_mut());
+        self.draw_hud();
+    }
 
+    // who is in the match, top left
+    fn draw_hud(&mut self) {
+        let mine = self.my_ids();
+        let roster = self.menu.roster.iter()
+            .map(|pl| format!("{}{}", pl.name, if mine.contains(&pl.id) { " (you)" } else { "" }))
+            .collect::<Vec<String>>()
+            .join("\n");
+        self.window.draw_text(&roster, &Point2::new(20.0, 20.0), 40.0, &Font::default(), &Point3::new(1.0, 1.0, 1.0));
+    }
 
+    fn run(&mut self) {
         loop {
-            let p = game.ch.recv_all();
+            self.poll();
 
-            p.iter().for_each(|packet| {
-                game.gameplay.on_packet(&mut game.ch, &packet.1, window);
-            });
+            if self.is_playing() {
+                self.update_playing();
+            } else {
+                self.draw_menu();
+            }
 
-            window.events().iter().for_each(|e| {
-                game.gameplay.on_event(&mut game.ch, &e.value, window);
-            });
+            if !self.window.render_with_camera(&mut self.cam) {
+                if self.conn.is_some() {
+                    self.leave();
+                    // give the poll thread a chance to flush the goodbye
+                    util::sleep(100);
+                }
+                return
+            }
+        }
+    }
+}
 
-            game.gameplay.update(&mut game.ch, window, &mut self.cam);
+// conn: the lobby we are in, if any
+fn gui(ui: &mut conrod::UiCell, ids: &Ids, menu: &mut Menu, conn: Option<&Connection>) -> Option<MenuAction> {
+    use conrod::color;
+    let mut action = None;
 
-            if !window.render_with_camera(&mut self.cam) {
-                return
+    widget::Canvas::new()
+        .pad(40.0)
+        .color(color::DARK_CHARCOAL)
+        .set(ids.canvas, ui);
+
+    widget::Text::new("gupier")
+        .font_size(32)
+        .color(color::WHITE)
+        .mid_top_of(ids.canvas)
+        .set(ids.title, ui);
+
+    match conn {
+        None => {
+            for event in widget::TextBox::new(&menu.address)
+                .font_size(16)
+                .w_h(320.0, 32.0)
+                .down(30.0)
+                .set(ids.address, ui)
+            {
+                match event {
+                    widget::text_box::Event::Update(text) => menu.address = text,
+                    widget::text_box::Event::Enter => action = Some(MenuAction::Connect),
+                }
             }
 
-        }
+            for event in widget::TextBox::new(&menu.name)
+                .font_size(16)
+                .w_h(320.0, 32.0)
+                .down(10.0)
+                .set(ids.name, ui)
+            {
+                match event {
+                    widget::text_box::Event::Update(text) => menu.name = text.chars().take(MAX_NAME_LEN).collect(),
+                    widget::text_box::Event::Enter => action = Some(MenuAction::Connect),
+                }
+            }
+
+            let preview = color::rgb(menu.color.0, menu.color.1, menu.color.2);
+            let mut rgb = [menu.color.0, menu.color.1, menu.color.2];
+            let sliders = [(ids.red, "red"), (ids.green, "green"), (ids.blue, "blue")];
+            for (i, (id, label)) in sliders.iter().enumerate() {
+                if let Some(value) = widget::Slider::new(rgb[i], 0.0, 1.0)
+                    .label(label)
+                    .color(preview)
+                    .w_h(320.0, 24.0)
+                    .down(10.0)
+                    .set(*id, ui)
+                {
+                    rgb[i] = value;
+                }
+            }
+            menu.color = (rgb[0], rgb[1], rgb[2]);
+
+            if widget::Button::new()
+                .label("Join")
+                .w_h(160.0, 36.0)
+                .down(20.0)
+                .set(ids.connect, ui)
+                .was_clicked()
+            {
+                action = Some(MenuAction::Connect);
+            }
+        },
+        Some(conn) => {
+            let roster = menu.roster.iter()
+                .map(|pl| format!("{} #{}{}{}", pl.name, pl.id,
+                    if pl.host { " (host)" } else { "" },
+                    if pl.ready { " - ready" } else { "" }))
+                .collect::<Vec<String>>()
+                .join("\n");
+            widget::Text::new(&roster)
+                .font_size(18)
+                .color(color::WHITE)
+                .down(30.0)
+                .set(ids.roster, ui);
+
+            if !menu.results.is_empty() {
+                widget::Text::new(&menu.results)
+                    .font_size(16)
+                    .color(color::YELLOW)
+                    .down(20.0)
+                    .set(ids.results, ui);
+            }
+
+            if conn.is_joined() {
+                for ready in widget::Toggle::new(menu.ready)
+                    .label(if menu.ready { "Ready" } else { "Not ready" })
+                    .w_h(160.0, 36.0)
+                    .down(20.0)
+                    .set(ids.ready, ui)
+                {
+                    action = Some(MenuAction::Ready(ready));
+                }
+
+                if menu.host && widget::Button::new()
+                    .label("Start")
+                    .w_h(160.0, 36.0)
+                    .down(10.0)
+                    .set(ids.start, ui)
+                    .was_clicked()
+                {
+                    action = Some(MenuAction::StartMatch);
+                }
+            }
+
+            if widget::Button::new()
+                .label("Leave")
+                .w_h(160.0, 36.0)
+                .down(10.0)
+                .set(ids.leave, ui)
+                .was_clicked()
+            {
+                action = Some(MenuAction::Leave);
+            }
+        },
     }
+
+    widget::Text::new(&menu.status)
+        .font_size(16)
+        .color(color::LIGHT_ORANGE)
+        .down(20.0)
+        .set(ids.status, ui);
+
+    action
+}
+
+// The token lets anybody take our players over: one file per server, in
+// a directory of the user only.
+fn session_path(addr: SocketAddr) -> Option<PathBuf> {
+    let cache = std::env::var_os("XDG_CACHE_HOME").map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .or_else(|| std::env::var_os("LOCALAPPDATA").map(PathBuf::from))?;
+    let file: String = addr.to_string().chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' { c } else { '_' })
+        .collect();
+    Some(cache.join("gupier").join("sessions").join(file))
+}
+
+fn save_session(addr: SocketAddr, token: u64) {
+    let path = match session_path(addr) {
+        Some(path) => path,
+        None => return println!("[c] cannot save session: no cache directory"),
+    };
+    if let Err(e) = write_private(&path, &token.to_string()) {
+        println!("[c] cannot save session: {}", e);
+    }
+}
+
+fn load_session(addr: SocketAddr) -> Option<u64> {
+    std::fs::read_to_string(session_path(addr)?).ok()?.trim().parse().ok()
+}
+
+fn forget_session(addr: SocketAddr) {
+    if let Some(path) = session_path(addr) {
+        std::fs::remove_file(path).ok();
+    }
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, data: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+    if let Some(dir) = path.parent() {
+        std::fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    }
+    let mut file = std::fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+    // the mode only applies to new files
+    file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    file.write_all(data.as_bytes())
 }
 
-pub fn connect<G>(host: String, gameplay: G)
+// the user profile is private already
+#[cfg(not(unix))]
+fn write_private(path: &Path, data: &str) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, data)
+}
+
+// Opens the window on the lobby menu, `autoconnect` joins `address` right away.
+// `local` is the client end of Channel::loopback when we run the server too
+pub fn start<G>(gameplay: G, address: String, autoconnect: bool, config: ClientConfig, local: Option<Channel>) -> Result<(), ComError>
 where G: GameplayClient {
-    let mut client = Client::new();
-    client.connect(host, gameplay);
-    client.join();
-    client.wait_start();
+    let mut client = Client::new(gameplay, address, config, local)?;
+    if autoconnect {
+        if let Err(e) = client.connect(client.config.rejoin) {
+            client.menu.status = e.to_string();
+        }
+    }
     client.run();
+    Ok(())
 }
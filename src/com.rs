@@ -1,87 +1,483 @@
-use laminar::{Config, Packet, Socket, SocketEvent};
+use laminar::{Config, DeliveryGuarantee, OrderingGuarantee, Packet, Socket, SocketEvent};
 use bincode::{deserialize, serialize};
-use std::sync::{Arc, RwLock};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::{Duration, Instant};
+use crate::link::{Conditioned, LinkConditions};
+
+#[derive(Debug)]
+pub enum ComError {
+    // address, reason
+    Bind(String, String),
+    Encode(String),
+    // the socket (or the other end of a loopback) is gone
+    Closed,
+}
+
+impl std::fmt::Display for ComError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ComError::Bind(addr, reason) => write!(f, "cannot listen on {}: {}", addr, reason),
+            ComError::Encode(reason) => write!(f, "cannot encode packet: {}", reason),
+            ComError::Closed => write!(f, "the socket is closed"),
+        }
+    }
+}
+
+// Moves laminar packets and events for a Channel: Udp goes over the
+// network, Loopback stays inside the process.
+pub trait Transport: Send + Sync {
+    fn send(&self, packet: Packet) -> Result<(), ComError>;
+    // Ok(None) if nothing is waiting
+    fn try_recv(&self) -> Result<Option<SocketEvent>, ComError>;
+    fn recv(&self) -> Result<SocketEvent, ComError>;
+    // Ok(None) if nothing arrived in time
+    fn recv_timeout(&self, timeout: Duration) -> Result<Option<SocketEvent>, ComError>;
+}
+
+fn try_recv<T>(rx: &crossbeam_channel::Receiver<T>) -> Result<Option<T>, ComError> {
+    match rx.try_recv() {
+        Ok(x) => Ok(Some(x)),
+        Err(crossbeam_channel::TryRecvError::Empty) => Ok(None),
+        Err(crossbeam_channel::TryRecvError::Disconnected) => Err(ComError::Closed),
+    }
+}
+
+fn recv_timeout<T>(rx: &crossbeam_channel::Receiver<T>, timeout: Duration) -> Result<Option<T>, ComError> {
+    match rx.recv_timeout(timeout) {
+        Ok(x) => Ok(Some(x)),
+        Err(crossbeam_channel::RecvTimeoutError::Timeout) => Ok(None),
+        Err(crossbeam_channel::RecvTimeoutError::Disconnected) => Err(ComError::Closed),
+    }
+}
+
+pub struct Udp {
+    tx: crossbeam_channel::Sender<laminar::Packet>,
+    rx: crossbeam_channel::Receiver<laminar::SocketEvent>,
+    _poll_thread: std::thread::JoinHandle<()>,
+}
+
+impl Udp {
+    pub fn bind(host: Option<String>) -> Result<Udp, ComError> {
+        let mut socket = match &host {
+            None => Socket::bind_with_config(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0), Config::default()),
+            Some(host) => Socket::bind(host.as_str()),
+        }.map_err(|e| ComError::Bind(host.unwrap_or_else(|| "a free port".to_string()), e.to_string()))?;
+
+        Ok(Udp {
+            tx: socket.get_packet_sender(),
+            rx: socket.get_event_receiver(),
+            _poll_thread: std::thread::spawn(move || socket.start_polling()),
+        })
+    }
+}
+
+impl Transport for Udp {
+    fn send(&self, packet: Packet) -> Result<(), ComError> {
+        self.tx.send(packet).map_err(|_| ComError::Closed)
+    }
+
+    fn try_recv(&self) -> Result<Option<SocketEvent>, ComError> {
+        try_recv(&self.rx)
+    }
+
+    fn recv(&self) -> Result<SocketEvent, ComError> {
+        self.rx.recv().map_err(|_| ComError::Closed)
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<Option<SocketEvent>, ComError> {
+        recv_timeout(&self.rx, timeout)
+    }
+}
+
+// One end of an in-process link, see Channel::loopback. Packets arrive
+// exactly once and in order, but keep the delivery they asked for so that
+// link::Conditioned can degrade them as it would over UDP.
+pub struct Loopback {
+    addr: SocketAddr,
+    peer: SocketAddr,
+    tx: crossbeam_channel::Sender<SocketEvent>,
+    rx: crossbeam_channel::Receiver<SocketEvent>,
+}
+
+impl Loopback {
+    pub fn pair(a: SocketAddr, b: SocketAddr) -> (Loopback, Loopback) {
+        let (to_a, from_b) = crossbeam_channel::unbounded();
+        let (to_b, from_a) = crossbeam_channel::unbounded();
+        (
+            Loopback { addr: a, peer: b, tx: to_b, rx: from_b },
+            Loopback { addr: b, peer: a, tx: to_a, rx: from_a },
+        )
+    }
+}
+
+impl Transport for Loopback {
+    fn send(&self, packet: Packet) -> Result<(), ComError> {
+        if packet.addr() != self.peer {
+            // like UDP, sending to nobody is not an error
+            println!("[com] no loopback peer at {}", packet.addr());
+            return Ok(())
+        }
+        // the peer sees it coming from us
+        let packet = readdressed(&packet, self.addr);
+        self.tx.send(SocketEvent::Packet(packet)).map_err(|_| ComError::Closed)
+    }
+
+    fn try_recv(&self) -> Result<Option<SocketEvent>, ComError> {
+        try_recv(&self.rx)
+    }
+
+    fn recv(&self) -> Result<SocketEvent, ComError> {
+        self.rx.recv().map_err(|_| ComError::Closed)
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<Option<SocketEvent>, ComError> {
+        recv_timeout(&self.rx, timeout)
+    }
+}
+
+// `packet` with another address, same guarantees
+fn readdressed(packet: &Packet, addr: SocketAddr) -> Packet {
+    let payload = packet.payload().to_vec();
+    match (packet.delivery_guarantee(), packet.order_guarantee()) {
+        (DeliveryGuarantee::Unreliable, OrderingGuarantee::None) => Packet::unreliable(addr, payload),
+        (DeliveryGuarantee::Unreliable, OrderingGuarantee::Sequenced(stream)) => Packet::unreliable_sequenced(addr, payload, stream),
+        (DeliveryGuarantee::Reliable, OrderingGuarantee::None) => Packet::reliable_unordered(addr, payload),
+        (DeliveryGuarantee::Reliable, OrderingGuarantee::Sequenced(stream)) => Packet::reliable_sequenced(addr, payload, stream),
+        // laminar only orders reliable packets
+        (_, OrderingGuarantee::Ordered(stream)) => Packet::reliable_ordered(addr, payload, stream),
+    }
+}
+
+// laminar only splits reliable packets in fragments, bigger unreliable
+// payloads go reliable instead of being refused
+const MAX_UNRELIABLE_SIZE: usize = 1200;
+
+// Compressed payloads start with this instead of the lobby prefix or a
+// variant tag, then the lz4 block with its size in front.
+const COMPRESSED_MAGIC: u32 = 0x6c7a_3401;
+// smaller payloads seldom get any smaller
+const MIN_COMPRESS_SIZE: usize = 128;
+// a compressed payload claiming more than this is garbage
+const MAX_DECOMPRESSED_SIZE: usize = 1 << 20;
+
+// What a Channel did to the payloads since it was created.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ChannelStats {
+    // bytes before and after compression, counting only the payloads that
+    // were compressed
+    pub sent: (u64, u64),
+    pub received: (u64, u64),
+    // unreliable payloads too big for one packet, sent reliably instead
+    pub reliable_fallbacks: u64,
+}
+
+impl std::fmt::Display for ChannelStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let pct = |(raw, packed): (u64, u64)| 100.0 * packed as f64 / raw.max(1) as f64;
+        write!(f, "sent {} bytes as {} ({:.0}%), received {} as {} ({:.0}%), {} unreliable payloads sent reliably",
+            self.sent.0, self.sent.1, pct(self.sent),
+            self.received.0, self.received.1, pct(self.received),
+            self.reliable_fallbacks,
+        )
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    // peers that agreed to get compressed payloads
+    compressed: HashSet<SocketAddr>,
+    stats: ChannelStats,
+}
+
+// Addresses of the server and client ends of Channel::loopback, nothing
+// is ever bound to them.
+pub fn loopback_addrs() -> (SocketAddr, SocketAddr) {
+    ("127.0.0.1:1".parse().unwrap(), "127.0.0.1:2".parse().unwrap())
+}
 
 #[allow(dead_code)]
 #[derive(Clone)]
 pub struct Channel {
-    tx: crossbeam_channel::Sender<laminar::Packet>,
-    rx: crossbeam_channel::Receiver<laminar::SocketEvent>,
-    poll_thread: Arc<RwLock<std::thread::JoinHandle<()>>>,
+    transport: Arc<dyn Transport>,
+    // shared by the clones, like the transport
+    shared: Arc<Mutex<Shared>>,
+}
+
+// What a Channel reports to its owner: either a payload or a change in the
+// state of a peer. `Disconnected` is sent by a peer that leaves on purpose
+// (see `Channel::disconnect`), `TimedOut` is raised by laminar.
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum Event<T> {
+    Connected(SocketAddr),
+    Packet(SocketAddr, T),
+    TimedOut(SocketAddr),
+    Disconnected(SocketAddr),
+}
+
+impl<T> Event<T> {
+    #[allow(dead_code)]
+    pub fn addr(&self) -> SocketAddr {
+        match self {
+            Event::Connected(addr) => *addr,
+            Event::Packet(addr, _) => *addr,
+            Event::TimedOut(addr) => *addr,
+            Event::Disconnected(addr) => *addr,
+        }
+    }
 }
 
 
 impl<'a> Channel {
     #[allow(dead_code)]
-    pub fn spawn(host: Option<String>) -> Channel {
-        let mut socket = if host.is_none() {
-            Socket::bind_with_config(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0), Config::default())
-        } else {
-            Socket::bind(host.unwrap())
-        }.unwrap();
+    pub fn spawn(host: Option<String>) -> Result<Channel, ComError> {
+        Ok(Channel::new(Udp::bind(host)?))
+    }
 
+    pub fn new<T>(transport: T) -> Channel
+    where T: Transport + 'static {
         Channel {
-            tx: socket.get_packet_sender(),
-            rx: socket.get_event_receiver(),
-            poll_thread: Arc::new(RwLock::new(std::thread::spawn(move || socket.start_polling()))),
+            transport: Arc::new(transport),
+            shared: Arc::new(Mutex::new(Shared::default())),
+        }
+    }
+
+    // Degrades what we receive as configured, see link::Conditioned.
+    pub fn conditioned(self, link: &LinkConditions) -> Channel {
+        if link.is_perfect() {
+            return self
+        }
+        println!("[com] simulating {:?}", link);
+        Channel {
+            transport: Arc::new(Conditioned::new(self.transport, link.clone())),
+            shared: self.shared,
         }
     }
 
+    // Whether what we send to addr gets compressed, the join handshake
+    // decides. Compressed payloads are understood either way.
+    pub fn set_compression(&mut self, addr: SocketAddr, on: bool) {
+        let mut shared = self.shared.lock().unwrap();
+        if on {
+            shared.compressed.insert(addr);
+        } else {
+            shared.compressed.remove(&addr);
+        }
+    }
+
+    pub fn stats(&self) -> ChannelStats {
+        self.shared.lock().unwrap().stats
+    }
+
+    // Counts the fallback, and tells about the first one.
+    fn fall_back_to_reliable(&self, size: usize) {
+        let mut shared = self.shared.lock().unwrap();
+        if shared.stats.reliable_fallbacks == 0 {
+            println!("[com] {} bytes are too many for an unreliable packet, sending reliably", size);
+        }
+        shared.stats.reliable_fallbacks+= 1;
+    }
+
+    // The server and client ends of an in-process link, reachable at the
+    // addresses of loopback_addrs.
     #[allow(dead_code)]
-    pub fn recv_all(&mut self) -> Vec<(SocketAddr, Vec<u8>)> {
-        let mut ret = vec![];
-        self.rx.try_iter().for_each(|packet| {
-            match packet {
-                SocketEvent::Connect(addr) => {
-                    println!("[com] connected {}", addr);
-                }
-                SocketEvent::Timeout(addr) => {
-                    println!("[com] timeout {}", addr);
-                }
-                SocketEvent::Packet(pkt) => {
-                    ret.push((pkt.addr(), pkt.payload().to_vec()));
+    pub fn loopback() -> (Channel, Channel) {
+        let (server, client) = loopback_addrs();
+        let (server, client) = Loopback::pair(server, client);
+        (Channel::new(server), Channel::new(client))
+    }
+
+    // Our payloads are never empty (bincode always writes the enum tag), so
+    // an empty packet is used as the "goodbye" marker. None if the payload
+    // does not decompress.
+    fn to_event(&self, event: SocketEvent) -> Option<Event<Vec<u8>>> {
+        Some(match event {
+            SocketEvent::Connect(addr) => Event::Connected(addr),
+            SocketEvent::Timeout(addr) => Event::TimedOut(addr),
+            SocketEvent::Packet(pkt) => {
+                if pkt.payload().is_empty() {
+                    Event::Disconnected(pkt.addr())
+                } else {
+                    Event::Packet(pkt.addr(), self.decompress(pkt.payload())?)
                 }
+            },
+        })
+    }
+
+    // Everything that is waiting, without blocking.
+    #[allow(dead_code)]
+    pub fn recv_all(&mut self) -> Result<Vec<Event<Vec<u8>>>, ComError> {
+        let mut events = vec![];
+        while let Some(event) = self.transport.try_recv()? {
+            events.extend(self.to_event(event));
+        }
+        Ok(events)
+    }
+
+    #[allow(dead_code)]
+    pub fn recv_raw(&mut self) -> Result<Event<Vec<u8>>, ComError> {
+        loop {
+            if let Some(event) = self.to_event(self.transport.recv()?) {
+                return Ok(event)
             }
-        });
-        ret
+        }
     }
 
+    // Ok(None) if nothing arrived in time.
     #[allow(dead_code)]
-    pub fn recv<T>(&mut self) -> Option<(SocketAddr, T)>
+    pub fn recv_raw_timeout(&mut self, timeout: Duration) -> Result<Option<Event<Vec<u8>>>, ComError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            let event = match self.transport.recv_timeout(deadline.saturating_duration_since(now))? {
+                Some(event) => event,
+                None => return Ok(None),
+            };
+            if let Some(event) = self.to_event(event) {
+                return Ok(Some(event))
+            }
+        }
+    }
+
+    // Skips the packets that do not decode as T.
+    #[allow(dead_code)]
+    pub fn recv<T>(&mut self) -> Result<Event<T>, ComError>
     where T: serde::de::DeserializeOwned {
-        while let Ok(pkt) = self.rx.recv() {
-            match pkt {
-                SocketEvent::Connect(addr) => {
-                    println!("[com] connected {}", addr);
-                }
-                SocketEvent::Timeout(addr) => {
-                    println!("[com] timeout {}", addr);
-                }
-                SocketEvent::Packet(pkt) => {
-                    // println!("[com] packet from {}", pkt.addr());
-                    let data = deserialize(&pkt.payload());
-                    if data.is_err() {
-                        println!("[com] invalid packet");
-                        continue;
-                    }
-                    return Some((pkt.addr(), data.unwrap()))
+        loop {
+            if let Some(event) = Channel::decode(self.recv_raw()?) {
+                return Ok(event)
+            }
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn recv_timeout<T>(&mut self, timeout: Duration) -> Result<Option<Event<T>>, ComError>
+    where T: serde::de::DeserializeOwned {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None)
+            }
+            let event = match self.recv_raw_timeout(deadline - now)? {
+                Some(event) => event,
+                None => return Ok(None),
+            };
+            if let Some(event) = Channel::decode(event) {
+                return Ok(Some(event))
+            }
+        }
+    }
+
+    fn decode<T>(event: Event<Vec<u8>>) -> Option<Event<T>>
+    where T: serde::de::DeserializeOwned {
+        match event {
+            Event::Packet(addr, payload) => {
+                // println!("[com] packet from {}", addr);
+                let data = deserialize(&payload);
+                if data.is_err() {
+                    println!("[com] invalid packet");
+                    return None
                 }
+                Some(Event::Packet(addr, data.unwrap()))
             }
+            Event::Connected(addr) => Some(Event::Connected(addr)),
+            Event::TimedOut(addr) => Some(Event::TimedOut(addr)),
+            Event::Disconnected(addr) => Some(Event::Disconnected(addr)),
         }
-        println!("[com] exit");
-        None
     }
 
     #[allow(dead_code)]
-    pub fn send_ro<T>(&mut self, addr: SocketAddr, x: T)
+    pub fn send_ro<T>(&mut self, addr: SocketAddr, x: T) -> Result<(), ComError>
+        where T: serde::Serialize {
+        self.send(Packet::reliable_ordered( addr, self.encode(addr, &x)?, None ))
+    }
+
+    #[allow(dead_code)]
+    pub fn send_rs<T>(&mut self, addr: SocketAddr, x: T) -> Result<(), ComError>
+        where T: serde::Serialize {
+        self.send(Packet::reliable_sequenced( addr, self.encode(addr, &x)?, None ))
+    }
+
+    // Fire and forget, for data that is stale by the time it could be resent.
+    #[allow(dead_code)]
+    pub fn send_unreliable<T>(&mut self, addr: SocketAddr, x: T) -> Result<(), ComError>
         where T: serde::Serialize {
-        self.tx.send(Packet::reliable_ordered( addr, serialize(&x).unwrap(), None )).expect("cannot send packet");
+        let payload = self.encode(addr, &x)?;
+        if payload.len() > MAX_UNRELIABLE_SIZE {
+            self.fall_back_to_reliable(payload.len());
+            return self.send(Packet::reliable_unordered( addr, payload ))
+        }
+        self.send(Packet::unreliable( addr, payload ))
     }
 
+    // Like send_unreliable, and the receiver drops anything older than
+    // what it already got.
     #[allow(dead_code)]
-    pub fn send_rs<T>(&mut self, addr: SocketAddr, x: T)
+    pub fn send_unreliable_sequenced<T>(&mut self, addr: SocketAddr, x: T) -> Result<(), ComError>
         where T: serde::Serialize {
-        self.tx.send(Packet::reliable_sequenced( addr, serialize(&x).unwrap(), None )).expect("cannot send packet");
+        let payload = self.encode(addr, &x)?;
+        if payload.len() > MAX_UNRELIABLE_SIZE {
+            self.fall_back_to_reliable(payload.len());
+            return self.send(Packet::reliable_sequenced( addr, payload, None ))
+        }
+        self.send(Packet::unreliable_sequenced( addr, payload, None ))
+    }
+
+    #[allow(dead_code)]
+    pub fn disconnect(&mut self, addr: SocketAddr) -> Result<(), ComError> {
+        self.send(Packet::reliable_ordered( addr, vec![], None ))
+    }
+
+    fn send(&mut self, packet: Packet) -> Result<(), ComError> {
+        self.transport.send(packet)
+    }
+
+    // Compressed if addr agreed to it and it pays off.
+    fn encode<T>(&self, addr: SocketAddr, x: &T) -> Result<Vec<u8>, ComError>
+    where T: serde::Serialize {
+        let payload = serialize(x).map_err(|e| ComError::Encode(e.to_string()))?;
+        if payload.len() < MIN_COMPRESS_SIZE {
+            return Ok(payload)
+        }
+        let mut shared = self.shared.lock().unwrap();
+        if !shared.compressed.contains(&addr) {
+            return Ok(payload)
+        }
+        let mut packed = COMPRESSED_MAGIC.to_le_bytes().to_vec();
+        packed.extend(lz4_flex::compress_prepend_size(&payload));
+        if packed.len() >= payload.len() {
+            return Ok(payload)
+        }
+        shared.stats.sent.0+= payload.len() as u64;
+        shared.stats.sent.1+= packed.len() as u64;
+        Ok(packed)
+    }
+
+    fn decompress(&self, payload: &[u8]) -> Option<Vec<u8>> {
+        if payload.len() < 8 || payload[..4] != COMPRESSED_MAGIC.to_le_bytes() {
+            return Some(payload.to_vec())
+        }
+        let mut size = [0; 4];
+        size.copy_from_slice(&payload[4..8]);
+        let size = u32::from_le_bytes(size) as usize;
+        if size > MAX_DECOMPRESSED_SIZE {
+            println!("[com] compressed packet too big: {} bytes", size);
+            return None
+        }
+        match lz4_flex::decompress(&payload[8..], size) {
+            Ok(data) => {
+                let mut shared = self.shared.lock().unwrap();
+                shared.stats.received.0+= data.len() as u64;
+                shared.stats.received.1+= payload.len() as u64;
+                Some(data)
+            },
+            Err(e) => {
+                println!("[com] cannot decompress packet: {:?}", e);
+                None
+            },
+        }
     }
 }
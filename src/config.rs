@@ -0,0 +1,38 @@
+use serde_derive::Deserialize;
+use std::path::Path;
+use crate::server::LobbyConfig;
+use crate::litio_host::MatchOptions;
+
+// Everything a dedicated server can be tuned with, loaded from a TOML file:
+//
+//   [lobby]
+//   bind = "0.0.0.0:20016"
+//   min_players = 2
+//
+//   [lobby.link]
+//   latency_ms = 100
+//
+//   [match]
+//   catcher_interval = 1000
+//
+//   [match.precision]
+//   position_bits = 12
+//
+//   [match.relevancy]
+//   radius = 50
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub lobby: LobbyConfig,
+    #[serde(rename = "match")]
+    pub game: MatchOptions,
+}
+
+impl ServerConfig {
+    pub fn load(path: &Path) -> Result<ServerConfig, String> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        toml::from_str(&data)
+            .map_err(|e| format!("invalid config {}: {}", path.display(), e))
+    }
+}
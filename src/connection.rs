@@ -0,0 +1,263 @@
+use crate::com::{Channel, ComError, Event};
+use crate::link::LinkConditions;
+use crate::server::{JoinRequest, Packet, Player, RejectReason, PROTOCOL_VERSION};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+// How the client and the bots join a lobby and stay in it.
+#[derive(Clone, Debug)]
+pub struct JoinConfig {
+    pub join_timeout: Duration,
+    pub join_retries: usize,
+    // how long to wait in the lobby for the match to start
+    pub start_timeout: Duration,
+    // players sharing the connection, e.g. split-screen
+    pub local_players: usize,
+    // simulated network trouble, for testing
+    pub link: LinkConditions,
+    // ask the server to compress the packets
+    pub compression: bool,
+}
+
+impl Default for JoinConfig {
+    fn default() -> Self {
+        JoinConfig {
+            join_timeout: Duration::from_secs(3),
+            join_retries: 3,
+            start_timeout: Duration::from_secs(600),
+            local_players: 1,
+            link: LinkConditions::default(),
+            compression: true,
+        }
+    }
+}
+
+impl JoinConfig {
+    pub fn request(&self, gameplay: &str, name: String, color: (f32, f32, f32), session: Option<u64>) -> JoinRequest {
+        JoinRequest {
+            version: PROTOCOL_VERSION,
+            gameplay: gameplay.to_string(),
+            color,
+            name,
+            local_players: self.local_players,
+            session,
+            compression: self.compression,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConnectionError {
+    InvalidHost(String),
+    Rejected(RejectReason),
+    NoAnswer,
+    ConnectionLost,
+    Network(ComError),
+}
+
+impl std::fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConnectionError::InvalidHost(host) => write!(f, "cannot resolve {}", host),
+            ConnectionError::Rejected(reason) => write!(f, "server refused to let us in: {}", reason),
+            ConnectionError::NoAnswer => write!(f, "server is not answering"),
+            ConnectionError::ConnectionLost => write!(f, "lost connection to server"),
+            ConnectionError::Network(e) => write!(f, "network error: {}", e),
+        }
+    }
+}
+
+pub fn resolve(host: &str) -> Result<SocketAddr, ConnectionError> {
+    host.to_socket_addrs().ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| ConnectionError::InvalidHost(host.to_string()))
+}
+
+enum State {
+    Joining { attempt: usize, deadline: Instant },
+    Joined { deadline: Instant, keepalive: Instant },
+    Playing,
+}
+
+// What the lobby told us, for the owner of the Connection to act on.
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum LobbyEvent {
+    // session token
+    Joined(u64),
+    Roster(Vec<Player>),
+    // seconds left before the match starts
+    Countdown(u32),
+    CountdownAborted,
+    GameStarted,
+    // final (player id, score) pairs, we are back in the lobby
+    GameOver(Vec<(usize, i32)>),
+    // a gameplay message, only while playing
+    Gameplay(Vec<u8>),
+}
+
+// The client side of the lobby protocol, without a window: joins with
+// retries, keeps the connection alive while waiting and follows the
+// server in and out of the matches.
+pub struct Connection {
+    ch: Channel,
+    addr: SocketAddr,
+    request: JoinRequest,
+    config: JoinConfig,
+    state: State,
+    // the players we control, in local order
+    ids: Vec<usize>,
+}
+
+impl Connection {
+    // Starts joining right away.
+    pub fn join(ch: Channel, addr: SocketAddr, request: JoinRequest, config: JoinConfig) -> Connection {
+        Connection {
+            ch,
+            addr,
+            request,
+            config,
+            state: State::Joining { attempt: 0, deadline: Instant::now() },
+            ids: vec![],
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn ids(&self) -> &[usize] {
+        &self.ids
+    }
+
+    pub fn channel(&mut self) -> &mut Channel {
+        &mut self.ch
+    }
+
+    #[allow(dead_code)]
+    pub fn is_joined(&self) -> bool {
+        match self.state { State::Joined { .. } => true, _ => false }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        match self.state { State::Playing => true, _ => false }
+    }
+
+    pub fn send(&mut self, packet: Packet) -> Result<(), ConnectionError> {
+        packet.send_to(&mut self.ch, self.addr).map_err(ConnectionError::Network)
+    }
+
+    // Best effort, the server times us out otherwise.
+    pub fn leave(mut self) {
+        self.ch.disconnect(self.addr).ok();
+        self.ch.set_compression(self.addr, false);
+    }
+
+    // Reads what arrived and sends what is due. Err once the connection is
+    // over, the owner should drop it.
+    pub fn update(&mut self) -> Result<Vec<LobbyEvent>, ConnectionError> {
+        let mut events = vec![];
+        for event in self.ch.recv_all().map_err(ConnectionError::Network)? {
+            if event.addr() != self.addr {
+                // leftovers of a previous server
+                continue
+            }
+            match event {
+                Event::Packet(_, data) => match Packet::decode(&data) {
+                    Some(packet) => events.extend(self.on_packet(packet)?),
+                    None => {
+                        if let State::Playing = self.state {
+                            events.push(LobbyEvent::Gameplay(data));
+                        }
+                    },
+                },
+                Event::TimedOut(_) => match self.state {
+                    // laminar gave up on the server, the next attempt reopens it
+                    State::Joining { attempt, .. } => {
+                        self.state = State::Joining { attempt, deadline: Instant::now() };
+                    },
+                    _ => return Err(ConnectionError::ConnectionLost),
+                },
+                Event::Disconnected(_) => return Err(ConnectionError::ConnectionLost),
+                Event::Connected(_) => {},
+            }
+        }
+        self.update_timers()?;
+        Ok(events)
+    }
+
+    fn send_join(&mut self, attempt: usize) -> Result<(), ConnectionError> {
+        if attempt > 0 {
+            println!("[c] {}: no answer from {}, retrying ({}/{})", self.request.name, self.addr, attempt, self.config.join_retries);
+        } else if self.request.session.is_some() {
+            println!("[c] {}: rejoining with saved session", self.request.name);
+        } else {
+            println!("[c] {}: joining lobby", self.request.name);
+        }
+        let req = self.request.clone();
+        self.send(Packet::Join(req))
+    }
+
+    fn update_timers(&mut self) -> Result<(), ConnectionError> {
+        let now = Instant::now();
+        match self.state {
+            State::Joining { attempt, deadline } if now >= deadline => {
+                if attempt > self.config.join_retries {
+                    return Err(ConnectionError::NoAnswer)
+                }
+                self.send_join(attempt)?;
+                self.state = State::Joining { attempt: attempt + 1, deadline: now + self.config.join_timeout };
+            },
+            State::Joined { deadline, .. } if now >= deadline => {
+                return Err(ConnectionError::NoAnswer)
+            },
+            State::Joined { deadline, keepalive } if now >= keepalive => {
+                // keep the connection from idling out while the lobby fills up
+                self.send(Packet::KeepAlive)?;
+                self.state = State::Joined { deadline, keepalive: now + Duration::from_secs(1) };
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    fn wait_start(&mut self) {
+        let now = Instant::now();
+        self.state = State::Joined { deadline: now + self.config.start_timeout, keepalive: now };
+    }
+
+    fn on_packet(&mut self, packet: Packet) -> Result<Option<LobbyEvent>, ConnectionError> {
+        Ok(match packet {
+            Packet::Joined(ids, token, compression) => match self.state {
+                State::Joining { .. } => {
+                    println!("[c] {}: joined as {:?}", self.request.name, ids);
+                    self.ids = ids;
+                    self.ch.set_compression(self.addr, compression);
+                    self.wait_start();
+                    Some(LobbyEvent::Joined(token))
+                },
+                _ => None,
+            },
+            Packet::Rejected(reason) => return Err(ConnectionError::Rejected(reason)),
+            Packet::Roster(roster) => Some(LobbyEvent::Roster(roster)),
+            Packet::Countdown(left) => Some(LobbyEvent::Countdown(left)),
+            Packet::CountdownAborted => Some(LobbyEvent::CountdownAborted),
+            Packet::GameStarted => match self.state {
+                State::Joined { .. } => {
+                    self.state = State::Playing;
+                    Some(LobbyEvent::GameStarted)
+                },
+                _ => None,
+            },
+            Packet::GameOver(results) => match self.state {
+                State::Playing => {
+                    println!("[c] {}: channel: {}", self.request.name, self.ch.stats());
+                    self.wait_start();
+                    Some(LobbyEvent::GameOver(results))
+                },
+                _ => None,
+            },
+            _ => None,
+        })
+    }
+}
@@ -1,20 +1,50 @@
 use crate::com::Channel;
+#[cfg(feature = "graphics")]
 use kiss3d::window::Window;
+#[cfg(feature = "graphics")]
 use kiss3d::event::WindowEvent;
 use std::collections::HashMap;
 use crate::server::Player;
 use std::net::SocketAddr;
 
 
+pub enum GameStatus {
+    Running,
+    // final (player id, score) pairs, best first
+    Over(Vec<(usize, i32)>),
+}
+
 pub trait GameplayHost {
+    // must match GameplayClient::gameplay_id of the clients
+    fn gameplay_id(&self) -> &'static str;
+    // called at the start of every match, rematches included
     fn init(&mut self, ch: &mut Channel, players: &HashMap::<usize, Player>);
-    fn update(&mut self, ch: &mut Channel, players: &HashMap::<usize, Player>);
-    fn on_packet(&mut self, ch: &mut Channel, sender: usize, tx: &[u8]);
+    fn update(&mut self, ch: &mut Channel, players: &HashMap::<usize, Player>) -> GameStatus;
+    // senders: the players of the connection, in local order
+    fn on_packet(&mut self, ch: &mut Channel, senders: &[usize], tx: &[u8]);
+    fn on_player_joined(&mut self, ch: &mut Channel, player: &Player);
+    fn on_player_left(&mut self, ch: &mut Channel, id: usize);
+    fn on_player_rejoined(&mut self, ch: &mut Channel, player: &Player);
 }
 
+#[cfg(feature = "graphics")]
 pub trait GameplayClient {
-    fn init(&mut self, id: usize, addr: SocketAddr);
+    fn gameplay_id(&self) -> &'static str;
+    // ids: the players of this client, one per camera
+    fn init(&mut self, ids: &[usize], addr: SocketAddr);
     fn on_event(&mut self, ch: &mut Channel, e: &WindowEvent, window: &mut Window);
-    fn update(&mut self, ch: &mut Channel, window: &mut Window, cam: &mut kiss3d::camera::FirstPerson);
+    fn update(&mut self, ch: &mut Channel, window: &mut Window, cams: &mut [kiss3d::camera::FirstPerson]);
     fn on_packet(&mut self, ch: &mut Channel, tx: &[u8], window: &mut Window);
+    // drop the scene and the state of the current game
+    fn reset(&mut self, window: &mut Window);
+}
+
+// Plays without a window, see bot.rs
+pub trait GameplayBot {
+    fn gameplay_id(&self) -> &'static str;
+    fn init(&mut self, ids: &[usize], addr: SocketAddr);
+    fn on_packet(&mut self, ch: &mut Channel, tx: &[u8]);
+    // called once per bot tick while playing
+    fn update(&mut self, ch: &mut Channel);
+    fn reset(&mut self);
 }
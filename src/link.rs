@@ -0,0 +1,219 @@
+use crate::com::{ComError, Transport};
+use crate::util;
+use laminar::{DeliveryGuarantee, OrderingGuarantee, Packet, SocketEvent};
+use serde_derive::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// How much worse than it is the link should look, for the packets we
+// receive. Set it on both ends to degrade both directions.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct LinkConditions {
+    pub latency_ms: u64,
+    // up to this much is added at random to the latency
+    pub jitter_ms: u64,
+    // fractions of the packets, from 0 to 1
+    pub loss: f32,
+    pub duplicate: f32,
+    pub reorder: f32,
+}
+
+impl LinkConditions {
+    pub fn is_perfect(&self) -> bool {
+        self.latency_ms == 0 && self.jitter_ms == 0
+            && self.loss <= 0.0 && self.duplicate <= 0.0 && self.reorder <= 0.0
+    }
+}
+
+// what a lost reliable packet costs on top of the latency, laminar has to
+// notice and send it again
+const RESEND_DELAY: Duration = Duration::from_millis(100);
+
+struct Pending {
+    due: Instant,
+    // arrival order, sequenced packets older than the last one are dropped
+    seq: u64,
+    packet: Packet,
+}
+
+#[derive(Default)]
+struct Peer {
+    // ordered packets never get out before this
+    ordered_due: Option<Instant>,
+    // newest sequenced packet handed out
+    sequenced: u64,
+}
+
+struct Queue {
+    pending: Vec<Pending>,
+    peers: HashMap<SocketAddr, Peer>,
+    // connects and timeouts go through untouched
+    events: VecDeque<SocketEvent>,
+    next_seq: u64,
+}
+
+impl Queue {
+    fn next_due(&self) -> Option<Instant> {
+        self.pending.iter().map(|p| p.due).min()
+    }
+
+    fn pop_due(&mut self) -> Option<SocketEvent> {
+        if let Some(event) = self.events.pop_front() {
+            return Some(event)
+        }
+        let now = Instant::now();
+        loop {
+            let i = self.pending.iter().enumerate()
+                .filter(|(_, p)| p.due <= now)
+                .min_by_key(|(_, p)| (p.due, p.seq))
+                .map(|(i, _)| i)?;
+            let p = self.pending.swap_remove(i);
+            if let OrderingGuarantee::Sequenced(_) = p.packet.order_guarantee() {
+                let peer = self.peers.entry(p.packet.addr()).or_default();
+                if p.seq <= peer.sequenced {
+                    continue
+                }
+                peer.sequenced = p.seq;
+            }
+            return Some(SocketEvent::Packet(p.packet))
+        }
+    }
+}
+
+// Holds back, drops, duplicates and shuffles what the wrapped transport
+// receives, in the ways laminar lets them through: lost reliable packets
+// only arrive late, ordered ones never overtake each other, stale
+// sequenced ones are dropped.
+pub struct Conditioned {
+    inner: Arc<dyn Transport>,
+    link: LinkConditions,
+    queue: Mutex<Queue>,
+}
+
+impl Conditioned {
+    pub fn new(inner: Arc<dyn Transport>, link: LinkConditions) -> Conditioned {
+        Conditioned {
+            inner,
+            link,
+            queue: Mutex::new(Queue {
+                pending: vec![],
+                peers: HashMap::new(),
+                events: VecDeque::new(),
+                next_seq: 1,
+            }),
+        }
+    }
+
+    fn jitter(&self) -> Duration {
+        Duration::from_millis(util::rand_float(0.0, self.link.jitter_ms as f32 + 1.0) as u64)
+    }
+
+    fn take(&self, q: &mut Queue, event: SocketEvent) {
+        let packet = match event {
+            SocketEvent::Packet(packet) => packet,
+            event => return q.events.push_back(event),
+        };
+        let reliable = match packet.delivery_guarantee() {
+            DeliveryGuarantee::Reliable => true,
+            DeliveryGuarantee::Unreliable => false,
+        };
+        let latency = Duration::from_millis(self.link.latency_ms);
+
+        let mut delay = latency + self.jitter();
+        if util::maybe(self.link.reorder) {
+            delay+= latency.max(Duration::from_millis(20));
+        }
+        if util::maybe(self.link.loss) {
+            if !reliable {
+                return
+            }
+            delay+= RESEND_DELAY + latency * 2;
+        }
+
+        let mut due = Instant::now() + delay;
+        if let OrderingGuarantee::Ordered(_) = packet.order_guarantee() {
+            let peer = q.peers.entry(packet.addr()).or_default();
+            due = due.max(peer.ordered_due.unwrap_or(due));
+            peer.ordered_due = Some(due);
+        }
+        let seq = q.next_seq;
+        q.next_seq+= 1;
+
+        // laminar drops the copies of reliable packets itself
+        if !reliable && util::maybe(self.link.duplicate) {
+            q.pending.push(Pending { due: due + self.jitter(), seq, packet: packet.clone() });
+        }
+        q.pending.push(Pending { due, seq, packet });
+    }
+
+    // Whatever is still held back gets delivered before a closed inner
+    // transport is reported.
+    fn intake(&self, q: &mut Queue) -> Result<(), ComError> {
+        loop {
+            match self.inner.try_recv() {
+                Ok(Some(event)) => self.take(q, event),
+                Ok(None) => return Ok(()),
+                Err(e) if q.pending.is_empty() && q.events.is_empty() => return Err(e),
+                Err(_) => return Ok(()),
+            }
+        }
+    }
+}
+
+impl Transport for Conditioned {
+    fn send(&self, packet: Packet) -> Result<(), ComError> {
+        self.inner.send(packet)
+    }
+
+    fn try_recv(&self) -> Result<Option<SocketEvent>, ComError> {
+        let mut q = self.queue.lock().unwrap();
+        self.intake(&mut q)?;
+        Ok(q.pop_due())
+    }
+
+    fn recv(&self) -> Result<SocketEvent, ComError> {
+        loop {
+            if let Some(event) = self.try_recv()? {
+                return Ok(event)
+            }
+            let next = self.queue.lock().unwrap().next_due();
+            let event = match next {
+                Some(due) => match self.inner.recv_timeout(due.saturating_duration_since(Instant::now())) {
+                    Ok(Some(event)) => event,
+                    // a held back packet is due, or the inner transport is
+                    // gone and the queue has to drain first
+                    _ => {
+                        util::sleep(due.saturating_duration_since(Instant::now()).as_millis() as u64);
+                        continue
+                    },
+                },
+                None => self.inner.recv()?,
+            };
+            self.take(&mut self.queue.lock().unwrap(), event);
+        }
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<Option<SocketEvent>, ComError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(event) = self.try_recv()? {
+                return Ok(Some(event))
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None)
+            }
+            let next = self.queue.lock().unwrap().next_due();
+            let wake = next.map_or(deadline, |due| due.min(deadline));
+            match self.inner.recv_timeout(wake.saturating_duration_since(now)) {
+                Ok(Some(event)) => self.take(&mut self.queue.lock().unwrap(), event),
+                Ok(None) => {},
+                Err(e) if next.is_none() => return Err(e),
+                Err(_) => util::sleep(wake.saturating_duration_since(Instant::now()).as_millis() as u64),
+            }
+        }
+    }
+}
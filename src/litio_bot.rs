@@ -0,0 +1,148 @@
+use crate::gameplay::*;
+use crate::litio_host::*;
+use crate::com::Channel;
+use crate::quantize::Quantizer;
+use crate::util;
+use nalgebra::base::Vector3;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+// how close the catcher gets before we run
+const FLEE_RADIUS: f32 = 15.0;
+// wanderers head back to the middle past this distance, not to fall off
+const WANDER_RADIUS: f32 = 30.0;
+// ticks between two changes of direction while wandering
+const WANDER_TICKS: usize = 120;
+
+#[derive(Debug, PartialEq)]
+enum Mood {
+    Wander,
+    // we are the catcher, go for the nearest player
+    Chase,
+    // the catcher is close
+    Flee,
+}
+
+pub struct Bot {
+    id: usize,
+    addr: SocketAddr,
+    things: HashMap<usize, LitioThing>,
+    state: Option<LitioUpdate>,
+    baselines: Baselines,
+    quantizer: Option<Quantizer>,
+    mood: Mood,
+    heading: Vector3<f32>,
+    ticks: usize,
+}
+
+impl Bot {
+    pub fn new() -> Bot {
+        Bot {
+            id: 0,
+            addr: "0.0.0.0:1".parse().unwrap(),
+            things: HashMap::new(),
+            state: None,
+            baselines: Baselines::new(),
+            quantizer: None,
+            mood: Mood::Wander,
+            heading: Vector3::zeros(),
+            ticks: 0,
+        }
+    }
+
+    // where to go and why
+    fn think(&mut self, state: &LitioUpdate) -> Option<(Mood, Vector3<f32>)> {
+        let q = self.quantizer.as_ref()?;
+        let me = q.unpack(state.transforms.get(&self.id)?).translation.vector;
+        let things = &self.things;
+        let others = state.transforms.iter()
+            .filter(|(id, _)| **id != self.id)
+            .filter(|(id, _)| match things.get(id) { Some(LitioThing::Player(_)) => true, _ => false })
+            .map(|(id, x)| (*id, q.unpack(x).translation.vector));
+
+        if state.catcher == self.id {
+            let nearest = others.min_by(|(_, a), (_, b)| {
+                (a - me).norm().partial_cmp(&(b - me).norm()).unwrap()
+            });
+            if let Some((_, target)) = nearest {
+                return Some((Mood::Chase, target - me))
+            }
+        } else if let Some(catcher) = state.transforms.get(&state.catcher) {
+            let away = me - q.unpack(catcher).translation.vector;
+            if away.norm() < FLEE_RADIUS {
+                return Some((Mood::Flee, away))
+            }
+        }
+
+        self.ticks+= 1;
+        if me.norm() > WANDER_RADIUS {
+            self.heading = -me;
+        } else if self.ticks % WANDER_TICKS == 1 {
+            let a = util::rand_float(0.0, 2.0 * std::f32::consts::PI);
+            self.heading = Vector3::new(a.cos(), 0.0, a.sin());
+        }
+        Some((Mood::Wander, self.heading))
+    }
+}
+
+impl GameplayBot for Bot {
+    fn gameplay_id(&self) -> &'static str {
+        "litio"
+    }
+
+    fn init(&mut self, ids: &[usize], addr: SocketAddr) {
+        self.id = ids[0];
+        self.addr = addr;
+    }
+
+    fn on_packet(&mut self, ch: &mut Channel, tx: &[u8]) {
+        match bincode::deserialize(tx).unwrap_or(Tx::Unknown) {
+            Tx::Spawn(id, thing) => { self.things.insert(id, thing); },
+            Tx::Despawn(id) => { self.things.remove(&id); },
+            Tx::Quantizer(q) => self.quantizer = Some(q),
+            Tx::Delta(delta) => {
+                if let Some(update) = self.baselines.apply(&delta) {
+                    if let Err(e) = ch.send_unreliable(self.addr, Tx::Ack(delta.tick)) {
+                        println!("[b] cannot ack snapshot: {}", e);
+                    }
+                    self.state = Some(update);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn update(&mut self, ch: &mut Channel) {
+        let state = match self.state.take() {
+            Some(state) => state,
+            // nothing new since the last tick
+            None => return,
+        };
+        let (mood, mut dir) = match self.think(&state) {
+            Some(x) => x,
+            None => return,
+        };
+        if mood != self.mood {
+            println!("[b] player {}: {:?}", self.id, mood);
+            self.mood = mood;
+        }
+
+        dir.y = 0.0;
+        if dir.norm() > 0.01 {
+            dir.normalize_mut();
+        }
+        // the host spins the ball around acc, so roll towards dir
+        let input = LitioPlayerInput {
+            acc: Vector3::y().cross(&dir),
+            look_at: Vector3::zeros(),
+            look_at_rot: dir,
+        };
+        if let Err(e) = ch.send_rs(self.addr, Tx::Input(0, input)) {
+            println!("[b] cannot send input: {}", e);
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Bot::new();
+    }
+}
@@ -1,11 +1,13 @@
 use crate::gameplay::*;
 use std::collections::HashMap;
 use kiss3d::window::Window;
-use kiss3d::event::WindowEvent;
+use kiss3d::event::{Key, WindowEvent};
 use kiss3d::event::WindowEvent::*;
+use kiss3d::camera::FirstPerson;
 use crate::litio_host::*;
 use kiss3d::scene::SceneNode;
 use crate::com::Channel;
+use crate::quantize::Quantizer;
 use na::geometry::Point3;
 use std::net::SocketAddr;
 use nalgebra::base::Vector3;
@@ -17,11 +19,28 @@ struct PlayerNodes {
     // life_core: SceneNode,
 }
 
+// one per split-screen viewport
+struct LocalPlayer {
+    id: usize,
+    input: LitioPlayerInput,
+}
+
+// forward, back, left, right of each local player
+const KEY_SETS: [[Key; 4]; 4] = [
+    [Key::W, Key::S, Key::A, Key::D],
+    [Key::Up, Key::Down, Key::Left, Key::Right],
+    [Key::I, Key::K, Key::J, Key::L],
+    [Key::Numpad8, Key::Numpad5, Key::Numpad4, Key::Numpad6],
+];
+
+// radians per frame for players that turn with the keyboard
+const TURN_SPEED: f32 = 0.04;
+
 #[allow(dead_code)]
 pub struct GameState {
-    player_id: usize,
+    // the first one looks around with the mouse, the others with the keys
+    locals: Vec<LocalPlayer>,
     addr: SocketAddr,
-    input: LitioPlayerInput,
     players: HashMap<usize, (LitioPlayer, PlayerNodes)>,
 }
 
@@ -29,18 +48,31 @@ impl GameState {
     pub fn new() -> GameState {
         GameState {
             addr: "0.0.0.0:1".parse().unwrap(),
-            player_id: 0,
-            input: LitioPlayerInput::new(),
+            locals: vec![],
             players: HashMap::new(),
         }
     }
 }
 
+fn look(input: &mut LitioPlayerInput, dx: f32, dy: f32) {
+    use std::f32::consts::PI as PI;
+    input.look_at.x+= dx;
+    input.look_at.y+= dy;
+    input.look_at.y = input.look_at.y.min(PI*0.49).max(-PI*0.49);
+    // println!("{:.2}, {:.2}", input.look_at.x, input.look_at.y);
+
+    input.look_at_rot.x = - input.look_at.y.cos() * input.look_at.x.sin();
+    input.look_at_rot.y = - input.look_at.y.sin();
+    input.look_at_rot.z = input.look_at.y.cos() * input.look_at.x.cos();
+}
+
 pub struct Client {
     _is_ready: bool,
     ps: GameState,
     nodes: HashMap<usize, SceneNode>,
     state: Option<LitioUpdate>,
+    baselines: Baselines,
+    quantizer: Option<Quantizer>,
 }
 
 impl Client {
@@ -50,11 +82,121 @@ impl Client {
             ps: GameState::new(),
             nodes: HashMap::new(),
             state: None,
+            baselines: Baselines::new(),
+            quantizer: None,
         }
     }
 
     fn is_ready(&self) -> bool {
-        self.state.is_some()
+        self.state.is_some() && self.quantizer.is_some()
+    }
+
+    fn spawn(&mut self, id: usize, thing: LitioThing, window: &mut Window) {
+        match thing {
+            LitioThing::Box(p) => {
+                if !self.nodes.contains_key(&id) {
+                    println!("creating BOX");
+                    let node = window.add_cube(p.dim.0 * 2.0, p.dim.1 * 2.0, p.dim.2 * 2.0);
+                    self.nodes.insert(id, node);
+                }
+                let node = self.nodes.get_mut(&id).expect("AS7D6");
+                node.set_color(p.color.0, p.color.1, p.color.2);
+            },
+            LitioThing::Player(p) => {
+                if !self.ps.players.contains_key(&id) {
+                    let mut nodes = PlayerNodes {
+                        sphere: window.add_sphere(1.0),
+                        // life: window.add_cube(1.5, 0.3, 0.3),
+                        // life_core: window.add_cube(1.5, 0.14, 0.14),
+                    };
+                    println!("creating PLAYER");
+                    nodes.sphere.set_texture_from_file(&std::path::Path::new("./tex.jpg"), "tex");
+                    self.ps.players.insert(id, (p.clone(), nodes));
+                }
+                let (pl, nodes) = self.ps.players.get_mut(&id).unwrap();
+                nodes.sphere.set_color(p.color.0, p.color.1, p.color.2);
+                *pl = p;
+            },
+        }
+    }
+
+    fn despawn(&mut self, id: usize, window: &mut Window) {
+        if let Some(mut node) = self.nodes.remove(&id) {
+            window.remove_node(&mut node);
+        }
+        if let Some((_, mut nodes)) = self.ps.players.remove(&id) {
+            println!("removing PLAYER {}", id);
+            window.remove_node(&mut nodes.sphere);
+        }
+    }
+
+    // Moves the spawned things where the snapshot says and hides the ones
+    // it leaves out. The transforms of things whose Spawn is still on its
+    // way wait in the state.
+    fn show(&mut self, update: LitioUpdate) {
+        let q = match &self.quantizer {
+            Some(q) => q,
+            None => {
+                self.state = Some(update);
+                return
+            },
+        };
+        for (id, iso) in update.transforms.iter() {
+            let iso = q.unpack(iso);
+            let mut old_iso = None;
+            if let Some(x) = &self.state {
+                old_iso = x.transforms.get(id).map(|x| q.unpack(x));
+            }
+            if let Some(node) = self.nodes.get_mut(id) {
+                // node.set_local_transformation(iso);
+                let mut loc = iso.translation;
+                let rot = iso.rotation;
+
+                if let Some(x) = old_iso {
+                    loc.x = (loc.x + x.translation.x) / 2.0;
+                    loc.y = (loc.y + x.translation.y) / 2.0;
+                    loc.z = (loc.z + x.translation.z) / 2.0;
+                }
+                node.set_local_translation(loc);
+                node.set_local_rotation(rot);
+            }
+            if let Some((_pl, nodes)) = self.ps.players.get_mut(id) {
+                let loc = iso.translation;
+                let rot = iso.rotation;
+
+                // Interpolation
+                // if let Some(x) = old_iso {
+                //     loc.x = (loc.x + x.translation.x) / 2.0;
+                //     loc.y = (loc.y + x.translation.y) / 2.0;
+                //     loc.z = (loc.z + x.translation.z) / 2.0;
+                // }
+
+                nodes.sphere.set_local_translation(loc);
+                nodes.sphere.set_local_rotation(rot);
+
+                // let mut life_loc = loc;
+                // life_loc.y+= 2.0;
+
+                // nodes.life_core.set_local_translation(life_loc);
+                // nodes.life_core.set_local_rotation(rot);
+                // nodes.life_core.set_color(1.0, 0.0, 0.);
+
+                // nodes.life.set_local_translation(life_loc);
+                // nodes.life.set_local_rotation(rot);
+                // nodes.life.set_color(0.0, 1.0, 0.);
+                // nodes.life.set_local_scale(1.5 * (_pl.life as f32/ 100.0), 0.3, 0.3);
+            }
+        }
+
+        // out of sight, the server stopped telling us where they are
+        for (id, node) in self.nodes.iter_mut() {
+            node.set_visible(update.transforms.contains_key(id));
+        }
+        for (id, (_, nodes)) in self.ps.players.iter_mut() {
+            nodes.sphere.set_visible(update.transforms.contains_key(id));
+        }
+
+        self.state = Some(update);
     }
 }
 
@@ -63,34 +205,30 @@ impl Client {
 
 
 impl GameplayClient for Client {
-    fn init(&mut self, id: usize, addr: SocketAddr) {
-        println!("[c] init gameplay");
-        self.ps.player_id = id;
+    fn gameplay_id(&self) -> &'static str {
+        "litio"
+    }
+
+    fn init(&mut self, ids: &[usize], addr: SocketAddr) {
+        println!("[c] init gameplay for {:?}", ids);
+        self.ps.locals = ids.iter().map(|id| LocalPlayer {
+            id: *id,
+            input: LitioPlayerInput::new(),
+        }).collect();
         self.ps.addr = addr;
     }
 
     fn on_event(&mut self, _ch: &mut Channel, e: &WindowEvent, window: &mut Window) {
-        use std::f64::consts::PI as PI;
         let w = window.width();
         let h = window.height();
-        let input = &mut self.ps.input;
 
         match e {
             CursorPos(x, y, _mods) => {
                 let dx = x - (w/2) as f64;
                 let dy = y - (h/2) as f64;
-                if dx.abs() > 0.0 {
-                    input.look_at.x+= (dx / 200.0) as f32;
+                if let Some(local) = self.ps.locals.first_mut() {
+                    look(&mut local.input, (dx / 200.0) as f32, (dy / 200.0) as f32);
                 }
-                if dy.abs() > 0.0 {
-                    input.look_at.y+= (dy / 200.0) as f32;
-                    input.look_at.y = input.look_at.y.min((PI*0.49) as f32).max((-PI*0.49) as f32);
-                }
-                // println!("{:.2}, {:.2}", input.look_at.x, input.look_at.y);
-
-                input.look_at_rot.x = - input.look_at.y.cos() * input.look_at.x.sin();
-                input.look_at_rot.y = - input.look_at.y.sin();
-                input.look_at_rot.z = input.look_at.y.cos() * input.look_at.x.cos();
                 window.set_cursor_position((w/2) as f64, (h/2) as f64);
             },
             Key(_key, _action, _modifiers) => {
@@ -99,80 +237,21 @@ impl GameplayClient for Client {
             _ => {},
         }
     }
-    fn on_packet(&mut self, _ch: &mut Channel, tx: &[u8], window: &mut Window) {
+    fn on_packet(&mut self, ch: &mut Channel, tx: &[u8], window: &mut Window) {
         let tx = bincode::deserialize(tx).unwrap_or(Tx::Unknown);
         match tx {
-            Tx::Update(update) => {
-                update.things.iter().for_each(|(id, info)| {
-                    let mut old_info = None;
-                    if let Some(x) = &self.state {
-                        old_info = x.things.get(id);
+            Tx::Spawn(id, thing) => self.spawn(id, thing, window),
+            Tx::Despawn(id) => self.despawn(id, window),
+            Tx::Quantizer(q) => self.quantizer = Some(q),
+            Tx::Delta(delta) => match self.baselines.apply(&delta) {
+                Some(update) => {
+                    if let Err(e) = ch.send_unreliable(self.ps.addr, Tx::Ack(delta.tick)) {
+                        println!("[c] cannot ack snapshot: {}", e);
                     }
-                    match &info.thing {
-                        LitioThing::Box(p) => {
-                            if !self.nodes.contains_key(id) {
-                                println!("creating BOX");
-                                let node = window.add_cube(p.dim.0 * 2.0, p.dim.1 * 2.0, p.dim.2 * 2.0);
-                                self.nodes.insert(*id, node);
-                            }
-                            let node = self.nodes.get_mut(id).expect("AS7D6");
-                            // node.set_local_transformation(info.iso);
-                            let mut loc = info.iso.translation;
-                            let rot = info.iso.rotation;
-
-                            if let Some(x) = old_info {
-                                loc.x = (loc.x + x.iso.translation.x) / 2.0;
-                                loc.y = (loc.y + x.iso.translation.y) / 2.0;
-                                loc.z = (loc.z + x.iso.translation.z) / 2.0;
-                            }
-                            node.set_local_translation(loc);
-                            node.set_local_rotation(rot);
-                            node.set_color(p.color.0, p.color.1, p.color.2);
-                        },
-                        LitioThing::Player(p) => {
-                            if !self.ps.players.contains_key(id) {
-                                let mut nodes = PlayerNodes {
-                                    sphere: window.add_sphere(1.0),
-                                    // life: window.add_cube(1.5, 0.3, 0.3),
-                                    // life_core: window.add_cube(1.5, 0.14, 0.14),
-                                };
-                                println!("creating PLAYER");
-                                nodes.sphere.set_texture_from_file(&std::path::Path::new("./tex.jpg"), "tex");
-                                self.ps.players.insert(*id, (p.clone(), nodes));
-                            }
-                            self.ps.players.get_mut(id).unwrap().0 = p.clone();
-                            let loc = info.iso.translation;
-                            let rot = info.iso.rotation;
-
-
-                            // Interpolation
-                            // if let Some(x) = old_info {
-                            //     loc.x = (loc.x + x.iso.translation.x) / 2.0;
-                            //     loc.y = (loc.y + x.iso.translation.y) / 2.0;
-                            //     loc.z = (loc.z + x.iso.translation.z) / 2.0;
-                            // }
-
-                            let (pl, nodes) = self.ps.players.get_mut(id).unwrap();
-                            nodes.sphere.set_local_translation(loc);
-                            nodes.sphere.set_local_rotation(rot);
-                            nodes.sphere.set_color(p.color.0, p.color.1, p.color.2);
-
-                            // let mut life_loc = loc;
-                            // life_loc.y+= 2.0;
-
-                            // nodes.life_core.set_local_translation(life_loc);
-                            // nodes.life_core.set_local_rotation(rot);
-                            // nodes.life_core.set_color(1.0, 0.0, 0.);
-
-                            // nodes.life.set_local_translation(life_loc);
-                            // nodes.life.set_local_rotation(rot);
-                            // nodes.life.set_color(0.0, 1.0, 0.);
-                            // nodes.life.set_local_scale(1.5 * (pl.life as f32/ 100.0), 0.3, 0.3);
-                        },
-                    }
-                });
-
-                self.state = Some(update);
+                    self.show(update);
+                },
+                // the server falls back to a full one once the base is too old there too
+                None => println!("[c] missing baseline for snapshot {}", delta.tick),
             },
             _ => {
                 println!("[c] tx not handled {:?}", tx);
@@ -180,69 +259,96 @@ impl GameplayClient for Client {
         }
     }
 
-    fn update(&mut self, ch: &mut Channel, window: &mut Window, cam: &mut kiss3d::camera::FirstPerson) {
+    fn reset(&mut self, window: &mut Window) {
+        self.nodes.values_mut().for_each(|node| window.remove_node(node));
+        self.ps.players.values_mut().for_each(|(_, nodes)| window.remove_node(&mut nodes.sphere));
+        self.nodes.clear();
+        self.ps = GameState::new();
+        self.state = None;
+        self.baselines = Baselines::new();
+        self.quantizer = None;
+        window.hide_cursor(false);
+    }
+
+    fn update(&mut self, ch: &mut Channel, window: &mut Window, cams: &mut [FirstPerson]) {
         if self.is_ready() {
             // window.set_cursor_grab(true);
             window.hide_cursor(true);
 
-            use kiss3d::event::Key;
             use kiss3d::event::Action::*;
             use std::f64::consts::PI as PI;
-            let a = self.ps.input.look_at.x - PI as f32 / 2.0;
-            let mut v = Vector3::zeros();
-            if window.get_key(Key::W) == Press {
-                v+= Vector3::new(-a.sin(), 0.0, a.cos());
-            }
-            if window.get_key(Key::S) == Press {
-                let a = a + PI as f32;
-                v+= Vector3::new(-a.sin(), 0.0, a.cos());
-            }
-            if window.get_key(Key::A) == Press {
-                let a = a - 0.5 * PI as f32;
-                v+= Vector3::new(-a.sin(), 0.0, a.cos());
-            }
-            if window.get_key(Key::D) == Press {
-                let a = a + 0.5 * PI as f32;
-                v+= Vector3::new(-a.sin(), 0.0, a.cos());
-            }
-            self.ps.input.acc = v;
-
-            let lar = self.ps.input.look_at_rot;
             let me = self.state.as_ref().unwrap();
-            let update = me.things.get(&self.ps.player_id).unwrap();
-            let pos = update.iso.translation;
-            // println!("[c] pos {:?}", pos);
+            let q = self.quantizer.as_ref().unwrap();
+            let addr = self.ps.addr;
 
-            // At center
-            // cam.look_at(
-            //     Point3::new(pos.x, pos.y, pos.z),
-            //     Point3::new(
-            //         pos.x + lar.x,
-            //         pos.y + lar.y,
-            //         pos.z + lar.z,
-            //     )
-            // );
+            for (i, (local, cam)) in self.ps.locals.iter_mut().zip(cams.iter_mut()).enumerate() {
+                let [forward, back, left, right] = KEY_SETS[i % KEY_SETS.len()];
+                let mouse = i == 0;
+                if !mouse {
+                    if window.get_key(left) == Press {
+                        look(&mut local.input, -TURN_SPEED, 0.0);
+                    }
+                    if window.get_key(right) == Press {
+                        look(&mut local.input, TURN_SPEED, 0.0);
+                    }
+                }
+
+                let a = local.input.look_at.x - PI as f32 / 2.0;
+                let mut v = Vector3::zeros();
+                if window.get_key(forward) == Press {
+                    v+= Vector3::new(-a.sin(), 0.0, a.cos());
+                }
+                if window.get_key(back) == Press {
+                    let a = a + PI as f32;
+                    v+= Vector3::new(-a.sin(), 0.0, a.cos());
+                }
+                // only the mouse player strafes, the others turn
+                if mouse && window.get_key(left) == Press {
+                    let a = a - 0.5 * PI as f32;
+                    v+= Vector3::new(-a.sin(), 0.0, a.cos());
+                }
+                if mouse && window.get_key(right) == Press {
+                    let a = a + 0.5 * PI as f32;
+                    v+= Vector3::new(-a.sin(), 0.0, a.cos());
+                }
+                local.input.acc = v;
+
+                let lar = local.input.look_at_rot;
+                let pos = match me.transforms.get(&local.id) {
+                    Some(iso) => q.unpack(iso).translation,
+                    None => continue,
+                };
+                // println!("[c] pos {:?}", pos);
+
+                // At center
+                // cam.look_at(
+                //     Point3::new(pos.x, pos.y, pos.z),
+                //     Point3::new(
+                //         pos.x + lar.x,
+                //         pos.y + lar.y,
+                //         pos.z + lar.z,
+                //     )
+                // );
 
-            // Look over
-            cam.look_at(
-                Point3::new(
-                    pos.x - lar.x * 6.0,
-                    pos.y - lar.y * 6.0 + 5.0,
-                    pos.z - lar.z * 6.0,
-                ),
-                Point3::new(
-                    pos.x,
-                    pos.y + 1.0,
-                    pos.z,
-                )
-            );
-
-
-            ch.send_rs(self.ps.addr, Tx::Input(LitioPlayerInput {
-                acc: self.ps.input.acc,
-                look_at: self.ps.input.look_at,
-                look_at_rot: self.ps.input.look_at_rot,
-            }));
+                // Look over
+                cam.look_at(
+                    Point3::new(
+                        pos.x - lar.x * 6.0,
+                        pos.y - lar.y * 6.0 + 5.0,
+                        pos.z - lar.z * 6.0,
+                    ),
+                    Point3::new(
+                        pos.x,
+                        pos.y + 1.0,
+                        pos.z,
+                    )
+                );
+
+
+                if let Err(e) = ch.send_rs(addr, Tx::Input(i, local.input.clone())) {
+                    println!("[c] cannot send input: {}", e);
+                }
+            }
             // cam.look_at(
             //     Point3::new(pos.x, pos.y, pos.z),
             //     Point3::new(0.0, 0.0, 0.0),
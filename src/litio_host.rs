@@ -1,11 +1,12 @@
 use crate::gameplay::*;
 use crate::world::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
 use serde_derive::{Deserialize, Serialize};
 use crate::com::Channel;
 use crate::server::Player;
 use crate::util;
-use nalgebra::geometry::Isometry3;
+use crate::quantize::{PackedIso, Precision, Quantizer};
 use nalgebra::base::Vector3;
 use util::uid;
 
@@ -24,18 +25,32 @@ impl LitioPlayerInput {
             look_at_rot: Vector3::zeros(),
         }
     }
+
+    // NaN and infinities would spread through the physics
+    pub fn is_finite(&self) -> bool {
+        [self.acc, self.look_at, self.look_at_rot].iter().all(|v| v.iter().all(|x| x.is_finite()))
+    }
 }
 
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Tx {
     Unknown,
-    Input(LitioPlayerInput),
-    Update(LitioUpdate),
+    // index of the local player on the sender, input
+    Input(usize, LitioPlayerInput),
+    // a thing came in, or looks different than it did
+    Spawn(usize, LitioThing),
+    Despawn(usize),
+    // how to read the transforms, sent before the first delta
+    Quantizer(Quantizer),
+    // the per-tick transforms, see LitioUpdate::delta
+    Delta(LitioDelta),
+    // the newest delta a client rebuilt, the next ones can build on it
+    Ack(u32),
 }
 
 #[allow(dead_code)]
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct LitioPlayer {
     pub color: (f32, f32, f32),
     pub life: i16,
@@ -55,7 +70,7 @@ impl LitioPlayer {
 
 
 #[allow(dead_code)]
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct LitioBox {
     pub color: (f32, f32, f32),
     pub dim: (f32, f32, f32),
@@ -64,70 +79,315 @@ pub struct LitioBox {
 
 
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum LitioThing {
     Box(LitioBox),
     Player(LitioPlayer),
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct LitioThingUpdate {
-    pub iso: Isometry3<f32>,
-    pub thing: LitioThing,
-}
-
-
+// Where everything is. What the things are travels apart, in Tx::Spawn,
+// only when it changes.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct LitioUpdate {
-    pub things: HashMap<usize, LitioThingUpdate>,
+    pub transforms: HashMap<usize, PackedIso>,
+    pub catcher: usize,
 }
 
 #[allow(dead_code)]
 impl LitioUpdate {
     pub fn new() -> LitioUpdate {
         LitioUpdate {
-            things: HashMap::new(),
+            transforms: HashMap::new(),
+            catcher: 0,
+        }
+    }
+
+    // What turns `base` into this snapshot: only the things that moved.
+    // Without a base every thing is in.
+    pub fn delta(&self, tick: u32, base: Option<(u32, &LitioUpdate)>) -> LitioDelta {
+        let empty = LitioUpdate::new();
+        let (base_tick, base) = match base {
+            Some((tick, base)) => (Some(tick), base),
+            None => (None, &empty),
+        };
+        LitioDelta {
+            tick,
+            base: base_tick,
+            changed: self.transforms.iter()
+                .filter(|(id, x)| base.transforms.get(id) != Some(x))
+                .map(|(id, x)| (*id, *x))
+                .collect(),
+            removed: base.transforms.keys()
+                .filter(|id| !self.transforms.contains_key(id))
+                .cloned()
+                .collect(),
+            catcher: self.catcher,
+        }
+    }
+
+    // The snapshot `delta` was made from, given its base.
+    pub fn apply(&self, delta: &LitioDelta) -> LitioUpdate {
+        let mut update = self.clone();
+        delta.removed.iter().for_each(|id| { update.transforms.remove(id); });
+        delta.changed.iter().for_each(|(id, x)| { update.transforms.insert(*id, *x); });
+        update.catcher = delta.catcher;
+        update
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LitioDelta {
+    pub tick: u32,
+    // tick of the snapshot this one builds on, None if it stands alone
+    pub base: Option<u32>,
+    pub changed: HashMap<usize, PackedIso>,
+    pub removed: Vec<usize>,
+    pub catcher: usize,
+}
+
+// snapshots the host and clients remember to build deltas on
+pub const SNAPSHOT_HISTORY: usize = 64;
+
+// The client end of the delta encoding: the snapshots rebuilt so far.
+pub struct Baselines {
+    history: VecDeque<(u32, LitioUpdate)>,
+}
+
+impl Baselines {
+    pub fn new() -> Baselines {
+        Baselines {
+            history: VecDeque::new(),
+        }
+    }
+
+    // None if the base of the delta was already forgotten
+    pub fn apply(&mut self, delta: &LitioDelta) -> Option<LitioUpdate> {
+        let update = match delta.base {
+            None => LitioUpdate::new().apply(delta),
+            Some(tick) => self.history.iter()
+                .find(|(t, _)| *t == tick)
+                .map(|(_, base)| base.apply(delta))?,
+        };
+        self.history.push_back((delta.tick, update.clone()));
+        while self.history.len() > SNAPSHOT_HISTORY {
+            self.history.pop_front();
+        }
+        Some(update)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct MatchOptions {
+    // ticks between two catcher elections
+    pub catcher_interval: usize,
+    // elections before the match is over, 0 to play forever
+    pub rounds: usize,
+    pub floor_size: f32,
+    pub tower_height: usize,
+    pub spawn_height: f32,
+    pub precision: Precision,
+    pub relevancy: Relevancy,
+}
+
+impl Default for MatchOptions {
+    fn default() -> Self {
+        MatchOptions {
+            catcher_interval: 1000,
+            rounds: 10,
+            floor_size: 100.0,
+            tower_height: 30,
+            spawn_height: 60.0,
+            precision: Precision::default(),
+            relevancy: Relevancy::default(),
+        }
+    }
+}
+
+// What the players of a connection get to see of the others: whatever is
+// close, whatever is in front of them within sight. Their own players and
+// the catcher are always in.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Relevancy {
+    pub enabled: bool,
+    pub radius: f32,
+    pub view_distance: f32,
+    // full angle of the view cone, in degrees
+    pub view_angle: f32,
+}
+
+impl Default for Relevancy {
+    fn default() -> Self {
+        Relevancy {
+            enabled: true,
+            radius: 30.0,
+            view_distance: 150.0,
+            view_angle: 120.0,
         }
     }
 }
 
 #[allow(dead_code)]
 pub struct Host {
+    options: MatchOptions,
+    quantizer: Quantizer,
     // from logic to physycs
     world: World,
     catcher: usize,
     time: usize,
+    round: usize,
+    // rounds each player spent not being the catcher
+    scores: HashMap<usize, i32>,
     things: HashMap<usize, LitioThing>,
     id2pl: HashMap<usize, usize>,
     last_input: HashMap<usize, LitioPlayerInput>,
+    // players that left, kept around in case they rejoin
+    departed: HashMap<usize, LitioPlayer>,
+    // the things as the clients know them, from the last Spawn and Despawn
+    announced: HashMap<usize, LitioThing>,
+    // the snapshots each connection (by its first player) was sent lately,
+    // and the newest one it acknowledged
+    history: HashMap<usize, VecDeque<(u32, LitioUpdate)>>,
+    acks: HashMap<usize, u32>,
 }
 
 impl Host {
-    pub fn new() -> Host {
+    pub fn new(options: MatchOptions) -> Host {
+        // the floor is 2 * floor_size wide, whatever falls off is gone anyway
+        let size = options.floor_size;
+        let quantizer = Quantizer::new(
+            [-size, -size, -size],
+            [size, options.spawn_height + size, size],
+            &options.precision,
+        );
         Host {
+            options,
+            quantizer,
             world: World::new(),
             things: HashMap::new(),
             catcher: 0,
             time: 0,
+            round: 0,
+            scores: HashMap::new(),
             id2pl: HashMap::new(),
             last_input: HashMap::new(),
+            departed: HashMap::new(),
+            announced: HashMap::new(),
+            history: HashMap::new(),
+            acks: HashMap::new(),
         }
     }
 
     pub fn gen_update(&self) -> LitioUpdate {
         let w = &self.world;
-        let things = self.things.iter().map(|(id, thing)| {
-            (*id, LitioThingUpdate {
-                thing: thing.clone(),
-                iso: match thing {
-                    LitioThing::Box(x) => w.get_iso(x.ph_node),
-                    LitioThing::Player(x) => w.get_iso(x.ph_node),
-                },
-            })
+        let transforms = self.things.iter().map(|(id, thing)| {
+            (*id, self.quantizer.pack(&match thing {
+                LitioThing::Box(x) => w.get_iso(x.ph_node),
+                LitioThing::Player(x) => w.get_iso(x.ph_node),
+            }))
         }).collect();
 
         LitioUpdate {
-            things,
+            transforms,
+            catcher: self.catcher,
+        }
+    }
+
+    // Center and bounding radius of a thing.
+    fn bounds(&self, id: usize) -> Option<(Vector3<f32>, f32)> {
+        Some(match self.things.get(&id)? {
+            LitioThing::Box(x) => (
+                self.world.get_iso(x.ph_node).translation.vector,
+                Vector3::new(x.dim.0, x.dim.1, x.dim.2).norm(),
+            ),
+            LitioThing::Player(x) => (self.world.get_iso(x.ph_node).translation.vector, 1.0),
+        })
+    }
+
+    // The part of `update` the players `ids`, sharing a connection, see.
+    fn relevant(&self, update: &LitioUpdate, ids: &[usize]) -> LitioUpdate {
+        let r = &self.options.relevancy;
+        if !r.enabled {
+            return update.clone()
+        }
+        // where each of our players is and which way it looks, if it said
+        // so: without a direction only the radius counts, a zero vector
+        // would pass the cone test for everything
+        let eyes: Vec<(Vector3<f32>, Option<Vector3<f32>>)> = ids.iter()
+            .filter_map(|id| {
+                let (pos, _) = self.bounds(*id)?;
+                let look = self.last_input.get(id).and_then(|x| x.look_at_rot.try_normalize(1.0e-6));
+                Some((pos, look))
+            })
+            .collect();
+        let cos = (r.view_angle.to_radians() / 2.0).cos();
+
+        let transforms = update.transforms.iter()
+            .filter(|(id, _)| {
+                if ids.contains(id) || **id == self.catcher {
+                    return true
+                }
+                let (center, size) = match self.bounds(**id) {
+                    Some(x) => x,
+                    None => return false,
+                };
+                eyes.iter().any(|(eye, look)| {
+                    let to = center - eye;
+                    let dist = (to.norm() - size).max(0.0);
+                    dist <= r.radius || match look {
+                        Some(look) => dist <= r.view_distance && to.dot(look) >= cos * to.norm(),
+                        None => false,
+                    }
+                })
+            })
+            .map(|(id, x)| (*id, *x))
+            .collect();
+
+        LitioUpdate {
+            transforms,
+            catcher: update.catcher,
+        }
+    }
+
+    // Spawns on every connection what is new or looks different since the
+    // last call, despawns what is gone.
+    fn announce(&mut self, ch: &mut Channel, addrs: &[SocketAddr]) {
+        let mut txs = vec![];
+        if self.announced.is_empty() {
+            // first call of the match
+            txs.push(Tx::Quantizer(self.quantizer.clone()));
+        }
+        for (id, thing) in self.things.iter() {
+            if self.announced.get(id) != Some(thing) {
+                txs.push(Tx::Spawn(*id, thing.clone()));
+            }
+        }
+        for id in self.announced.keys() {
+            if !self.things.contains_key(id) {
+                txs.push(Tx::Despawn(*id));
+            }
+        }
+        for addr in addrs {
+            for tx in &txs {
+                if let Err(e) = ch.send_ro(*addr, tx) {
+                    println!("[s] cannot send to {}: {}", addr, e);
+                }
+            }
+        }
+        self.announced = self.things.clone();
+    }
+
+    // Brings a new connection up to what the others were told, the
+    // transforms follow with the next delta.
+    fn catch_up(&self, ch: &mut Channel, addr: SocketAddr) {
+        if let Err(e) = ch.send_ro(addr, Tx::Quantizer(self.quantizer.clone())) {
+            println!("[s] cannot send to {}: {}", addr, e);
+        }
+        for (id, thing) in self.announced.iter() {
+            if let Err(e) = ch.send_ro(addr, Tx::Spawn(*id, thing.clone())) {
+                println!("[s] cannot send to {}: {}", addr, e);
+            }
         }
     }
 
@@ -138,7 +398,8 @@ impl Host {
 
         let w = &mut self.world;
 
-        let dim = (100.0, 0.1, 100.0);
+        let size = self.options.floor_size;
+        let dim = (size, 0.1, size);
         let lbox = LitioBox {
             color: util::rand_color(),
             dim,
@@ -149,7 +410,7 @@ impl Host {
         self.things.insert(uid(), LitioThing::Box(lbox));
 
         for a in 0..2 {
-            for b in 0..30 {
+            for b in 0..self.options.tower_height {
                 for c in 0..2 {
                     let dim = (rf(0.5,0.9), 1.0, rf(0.3,1.2));
                     let lbox = LitioBox {
@@ -164,6 +425,17 @@ impl Host {
         }
     }
 
+    fn spawn_player(&mut self, id: usize) -> &mut LitioPlayer {
+        let pl = LitioPlayer::new(
+            (0.0, 1.0, 0.0),
+            self.world.add_ball(1.0),
+        );
+        self.id2pl.insert(pl.ph_node, id);
+        self.world.set_pos(pl.ph_node, &(0.5, self.options.spawn_height, 1.0));
+        self.things.insert(id, LitioThing::Player(pl));
+        self.pl(id)
+    }
+
     fn pl(&mut self, id: usize) -> &mut LitioPlayer {
         match self.things.get_mut(&id).expect("cannot find player") {
             LitioThing::Player(p) => p,
@@ -171,13 +443,34 @@ impl Host {
         }
     }
 
-    fn elect_catcher(&mut self, players: &HashMap::<usize, Player>) {
-        let ids: Vec<usize> = players.keys().cloned().collect();
-        self.catcher = util::pick(&ids);
+    fn elect_catcher(&mut self) {
+        let ids: Vec<usize> = self.things.iter()
+            .filter(|(_, thing)| match thing { LitioThing::Player(_) => true, _ => false })
+            .map(|(id, _)| *id)
+            .collect();
+        self.catcher = if ids.is_empty() { 0 } else { util::pick(&ids) };
+    }
+
+    fn end_round(&mut self) {
+        self.round+= 1;
+        let catcher = self.catcher;
+        let scores = &mut self.scores;
+        self.things.iter().for_each(|(id, thing)| {
+            if let LitioThing::Player(_) = thing {
+                *scores.entry(*id).or_insert(0)+= if *id == catcher { 0 } else { 1 };
+            }
+        });
+        println!("[s] round {} over", self.round);
+    }
+
+    fn results(&self) -> Vec<(usize, i32)> {
+        let mut results: Vec<(usize, i32)> = self.scores.iter().map(|(id, score)| (*id, *score)).collect();
+        results.sort_by_key(|(_, score)| -score);
+        results
     }
 
     fn toggle_colors(&mut self, id: usize) {
-        if let LitioThing::Player(x) = self.things.get_mut(&id).expect(&format!("cannot find player to toggle_colors for {}", id)) {
+        if let Some(LitioThing::Player(x)) = self.things.get_mut(&id) {
             let g = x.color.1;
             x.color.1 = x.color.0;
             x.color.0 = g;
@@ -187,35 +480,88 @@ impl Host {
 
 
 impl GameplayHost for Host {
+    fn gameplay_id(&self) -> &'static str {
+        "litio"
+    }
     fn init(&mut self, _ch: &mut Channel, players: &HashMap::<usize, Player>) {
-        players.iter().for_each(|(id, _player)| {
-            let pl = LitioPlayer::new(
-                (0.0, 1.0, 0.0),
-                self.world.add_ball(1.0),
-            );
-            self.id2pl.insert(pl.ph_node, *id);
-            self.world.set_pos(pl.ph_node, &(0.5, 60.0, 1.0));
-            self.things.insert(*id, LitioThing::Player(pl));
-        });
+        // start every match from a clean world
+        *self = Host::new(self.options.clone());
+        for id in players.keys() {
+            self.spawn_player(*id);
+        }
         self.init_map();
-        self.elect_catcher(players);
+        self.elect_catcher();
         self.toggle_colors(self.catcher);
         println!("[s] gameplay initiated");
     }
-    fn on_packet(&mut self, _ch: &mut Channel, player_id: usize, tx: &[u8]) {
+    fn on_packet(&mut self, _ch: &mut Channel, senders: &[usize], tx: &[u8]) {
 
         let tx: Tx = bincode::deserialize(tx).unwrap_or(Tx::Unknown);
         // println!("[s] rec packet {:?}", tx);
         match tx {
-            Tx::Input(x) => {
-                self.last_input.insert(player_id, x);
+            Tx::Input(local, x) => match senders.get(local) {
+                Some(id) if !x.is_finite() => println!("[s] invalid input from player {}", id),
+                Some(id) => {
+                    self.last_input.insert(*id, x);
+                },
+                None => println!("[s] input for unknown local player {}", local),
+            },
+            Tx::Ack(tick) => {
+                if let Some(first) = senders.first() {
+                    // acks travel unreliably and may come in out of order
+                    let ack = self.acks.entry(*first).or_insert(tick);
+                    *ack = tick.max(*ack);
+                }
             },
             _ => {
                 println!("[s] tx not handled: {:?}", tx);
             }
         }
     }
-    fn update(&mut self, ch: &mut Channel, players: &HashMap::<usize, Player>) {
+    fn on_player_joined(&mut self, ch: &mut Channel, player: &Player) {
+        self.spawn_player(player.id);
+        if !self.things.contains_key(&self.catcher) {
+            self.elect_catcher();
+            self.toggle_colors(self.catcher);
+        }
+        self.catch_up(ch, player.addr);
+        println!("[s] spawned late player {}", player.id);
+    }
+    fn on_player_left(&mut self, _ch: &mut Channel, id: usize) {
+        self.last_input.remove(&id);
+        // a new connection starts without baselines
+        self.history.remove(&id);
+        self.acks.remove(&id);
+        if self.catcher == id {
+            // store the player with its normal colors
+            self.toggle_colors(id);
+        }
+        if let Some(LitioThing::Player(pl)) = self.things.remove(&id) {
+            self.id2pl.remove(&pl.ph_node);
+            self.world.remove_entity(pl.ph_node);
+            self.departed.insert(id, pl);
+        }
+        if self.catcher == id {
+            self.elect_catcher();
+            self.toggle_colors(self.catcher);
+        }
+        println!("[s] despawned player {}", id);
+    }
+    fn on_player_rejoined(&mut self, ch: &mut Channel, player: &Player) {
+        let old = self.departed.remove(&player.id);
+        let pl = self.spawn_player(player.id);
+        if let Some(old) = old {
+            pl.color = old.color;
+            pl.life = old.life;
+        }
+        if !self.things.contains_key(&self.catcher) {
+            self.elect_catcher();
+            self.toggle_colors(self.catcher);
+        }
+        self.catch_up(ch, player.addr);
+        println!("[s] respawned player {}", player.id);
+    }
+    fn update(&mut self, ch: &mut Channel, players: &HashMap::<usize, Player>) -> GameStatus {
         use nphysics3d::algebra::*;
         use nphysics3d::algebra::ForceType;
         use nphysics3d::object::Body;
@@ -227,7 +573,7 @@ impl GameplayHost for Host {
 
         self.last_input.iter().for_each(|(id, input)| {
             // println!("input: {:#?}", input);
-            if let LitioThing::Player(pl) = things.get_mut(id).unwrap() {
+            if let Some(LitioThing::Player(pl)) = things.get_mut(id) {
                 // pl.life = util::rand_usize(100) as i16;
                 let body = w.get_rigid_mut(pl.ph_node);
                 // pl.set_angular_velocity(cur_vel * 0.01 + input.acc * 10.0);
@@ -238,9 +584,13 @@ impl GameplayHost for Host {
 
         });
 
-        if self.time % 1000 == 0 {
+        if self.time % self.options.catcher_interval.max(1) == 0 {
+            self.end_round();
+            if self.options.rounds > 0 && self.round >= self.options.rounds {
+                return GameStatus::Over(self.results())
+            }
             self.toggle_colors(self.catcher);
-            self.elect_catcher(players);
+            self.elect_catcher();
             self.toggle_colors(self.catcher);
         }
 
@@ -248,18 +598,21 @@ impl GameplayHost for Host {
         for contact in self.world.geometrical_world.contact_events() {
             match contact {
                 ncollide3d::pipeline::narrow_phase::ContactEvent::Started(h1, h2) => {
-                    let h1 = self.world.col2id.get(h1).unwrap();
-                    let h2 = self.world.col2id.get(h2).unwrap();
+                    let (h1, h2) = match (self.world.col2id.get(h1), self.world.col2id.get(h2)) {
+                        (Some(h1), Some(h2)) => (h1, h2),
+                        // one of the bodies was despawned in the meantime
+                        _ => continue,
+                    };
                     println!("contact {} {}, {:?}", h1, h2, self.id2pl);
                     if self.id2pl.contains_key(&h1) && self.id2pl.contains_key(&h2) {
                         println!("ok");
                         let id1 = self.id2pl.get(&h1).unwrap();
                         let id2 = self.id2pl.get(&h2).unwrap();
 
-                        if let LitioThing::Player(p) = self.things.get_mut(&id1).unwrap() {
+                        if let Some(LitioThing::Player(p)) = self.things.get_mut(&id1) {
                              p.color.1-= 0.2
                         }
-                        if let LitioThing::Player(p) = self.things.get_mut(&id2).unwrap() {
+                        if let Some(LitioThing::Player(p)) = self.things.get_mut(&id2) {
                              p.color.1-= 0.2
                         }
                     }
@@ -270,6 +623,95 @@ impl GameplayHost for Host {
 
         // println!("send updates to {} players: {:#?}", players.len(), update);
         let update = self.gen_update();
-        players.values().for_each(|p| ch.send_ro(p.addr, Tx::Update(update.clone())));
+        let tick = self.time as u32;
+
+        // split-screen players share a connection, known by its first player
+        let mut conns: HashMap<SocketAddr, Vec<usize>> = HashMap::new();
+        players.values().for_each(|p| conns.entry(p.addr).or_default().push(p.id));
+        let addrs: Vec<SocketAddr> = conns.keys().cloned().collect();
+        self.announce(ch, &addrs);
+
+        // a lost snapshot is superseded by the next one, no point resending it
+        for (addr, mut ids) in conns {
+            ids.sort();
+            let first = ids[0];
+            let update = self.relevant(&update, &ids);
+            let history = self.history.entry(first).or_default();
+            let base = self.acks.get(&first)
+                .and_then(|ack| history.iter().find(|(t, _)| t == ack))
+                .map(|(t, base)| (*t, base));
+            if let Err(e) = ch.send_unreliable_sequenced(addr, Tx::Delta(update.delta(tick, base))) {
+                println!("[s] cannot send to {}: {}", addr, e);
+            }
+
+            history.push_back((tick, update));
+            while history.len() > SNAPSHOT_HISTORY {
+                history.pop_front();
+            }
+        }
+        GameStatus::Running
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::geometry::Isometry3;
+
+    fn at(x: f32) -> PackedIso {
+        let q = Quantizer::new([-100.0; 3], [100.0; 3], &Precision::default());
+        q.pack(&Isometry3::translation(x, 0.0, 0.0))
+    }
+
+    fn snapshot(things: &[(usize, f32)], catcher: usize) -> LitioUpdate {
+        LitioUpdate {
+            transforms: things.iter().map(|(id, x)| (*id, at(*x))).collect(),
+            catcher,
+        }
+    }
+
+    fn assert_same(a: &LitioUpdate, b: &LitioUpdate) {
+        assert_eq!(a.transforms, b.transforms);
+        assert_eq!(a.catcher, b.catcher);
+    }
+
+    #[test]
+    fn full_snapshots_round_trip() {
+        let update = snapshot(&[(1, 0.0), (2, 10.0), (3, -20.0)], 2);
+        let delta = update.delta(5, None);
+        assert_eq!(delta.base, None);
+        assert_eq!(delta.changed.len(), 3);
+        assert!(delta.removed.is_empty());
+
+        assert_same(&Baselines::new().apply(&delta).unwrap(), &update);
+    }
+
+    #[test]
+    fn deltas_carry_only_what_changed_since_the_base() {
+        let base = snapshot(&[(1, 0.0), (2, 10.0), (3, -20.0)], 2);
+        let update = snapshot(&[(1, 0.0), (2, 11.0), (4, 30.0)], 4);
+        let delta = update.delta(6, Some((5, &base)));
+        assert_eq!(delta.base, Some(5));
+        let mut changed: Vec<usize> = delta.changed.keys().cloned().collect();
+        changed.sort();
+        assert_eq!(changed, vec![2, 4]);
+        assert_eq!(delta.removed, vec![3]);
+
+        assert_same(&base.apply(&delta), &update);
+        let mut baselines = Baselines::new();
+        baselines.apply(&base.delta(5, None)).unwrap();
+        assert_same(&baselines.apply(&delta).unwrap(), &update);
+    }
+
+    #[test]
+    fn deltas_on_forgotten_bases_are_refused() {
+        let mut baselines = Baselines::new();
+        for tick in 0..=SNAPSHOT_HISTORY as u32 {
+            baselines.apply(&snapshot(&[(1, tick as f32)], 0).delta(tick, None)).unwrap();
+        }
+        let update = snapshot(&[(1, 50.0)], 0);
+        // tick 0 dropped out, tick 1 is the oldest one left
+        assert!(baselines.apply(&update.delta(100, Some((0, &snapshot(&[(1, 0.0)], 0))))).is_none());
+        assert!(baselines.apply(&update.delta(101, Some((1, &snapshot(&[(1, 1.0)], 0))))).is_some());
     }
 }
@@ -1,3 +1,4 @@
+#[cfg(feature = "graphics")]
 extern crate kiss3d;
 extern crate nalgebra as na;
 extern crate serde;
@@ -5,14 +6,29 @@ extern crate serde;
 mod world;
 mod util;
 mod server;
+#[cfg(feature = "graphics")]
 mod client;
 mod com;
+mod link;
+mod quantize;
+mod config;
+#[cfg(feature = "graphics")]
+mod split_camera;
 
 mod litio_host;
+#[cfg(feature = "graphics")]
 mod litio_client;
+mod litio_bot;
 mod gameplay;
+mod bot;
+mod connection;
 use structopt::StructOpt;
+use std::path::PathBuf;
+use std::net::SocketAddr;
+use std::time::Duration;
 
+// the client options go unused in headless builds
+#[cfg_attr(not(feature = "graphics"), allow(dead_code))]
 #[derive(Debug, StructOpt)]
 #[structopt(name = "example", about = "An example of StructOpt usage.")]
 struct Opt {
@@ -21,23 +37,223 @@ struct Opt {
 
     #[structopt(short, long)]
     connect: Option<String>,
+
+    /// Run N headless bots against --connect (or the local server)
+    #[structopt(long)]
+    bot: Option<usize>,
+
+    /// Reclaim the player of the last session saved for this server
+    #[structopt(short, long)]
+    rejoin: bool,
+
+    /// TOML file with [lobby] and [match] sections
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// Address the server listens on, overrides --serve
+    #[structopt(long)]
+    bind: Option<String>,
+
+    #[structopt(long)]
+    min_players: Option<usize>,
+
+    #[structopt(long)]
+    max_players: Option<usize>,
+
+    /// Server updates per second
+    #[structopt(long)]
+    tick_rate: Option<u64>,
+
+    /// Ticks between two catcher elections
+    #[structopt(long)]
+    catcher_interval: Option<usize>,
+
+    /// Catcher elections before the match is over, 0 to play forever
+    #[structopt(long)]
+    rounds: Option<usize>,
+
+    /// Seconds to wait for the server to answer a join
+    #[structopt(long, default_value = "3")]
+    join_timeout: u64,
+
+    #[structopt(long, default_value = "3")]
+    join_retries: usize,
+
+    /// Seconds to wait in the lobby for the game to start
+    #[structopt(long, default_value = "600")]
+    start_timeout: u64,
+
+    /// Players sharing this screen, up to 4 (WASD+mouse, arrows, IJKL, numpad)
+    #[structopt(long, default_value = "1")]
+    local_players: usize,
+
+    /// Simulated latency of the packets we receive, in ms
+    #[structopt(long)]
+    latency: Option<u64>,
+
+    /// Up to this many ms added at random to the simulated latency
+    #[structopt(long)]
+    jitter: Option<u64>,
+
+    /// Fraction of the received packets to lose, from 0 to 1
+    #[structopt(long)]
+    loss: Option<f32>,
+
+    /// Fraction of the received packets to duplicate, from 0 to 1
+    #[structopt(long)]
+    duplicate: Option<f32>,
+
+    /// Fraction of the received packets to hold back, from 0 to 1
+    #[structopt(long)]
+    reorder: Option<f32>,
+
+    /// Neither ask for nor grant packet compression
+    #[structopt(long)]
+    no_compression: bool,
+}
+
+// the config file may already degrade the link, flags override it
+fn link_conditions(opt: &Opt, mut link: link::LinkConditions) -> link::LinkConditions {
+    if let Some(x) = opt.latency {
+        link.latency_ms = x;
+    }
+    if let Some(x) = opt.jitter {
+        link.jitter_ms = x;
+    }
+    if let Some(x) = opt.loss {
+        link.loss = x;
+    }
+    if let Some(x) = opt.duplicate {
+        link.duplicate = x;
+    }
+    if let Some(x) = opt.reorder {
+        link.reorder = x;
+    }
+    link
+}
+
+fn server_config(opt: &Opt) -> config::ServerConfig {
+    let mut config = match &opt.config {
+        Some(path) => config::ServerConfig::load(path).unwrap_or_else(|e| {
+            println!("{}", e);
+            std::process::exit(1);
+        }),
+        None => config::ServerConfig::default(),
+    };
+
+    if let Some(port) = opt.serve {
+        config.lobby.bind = format!("0.0.0.0:{}", port);
+    }
+    if let Some(bind) = &opt.bind {
+        config.lobby.bind = bind.clone();
+    }
+    if let Some(x) = opt.min_players {
+        config.lobby.min_players = x;
+    }
+    if let Some(x) = opt.max_players {
+        config.lobby.max_players = x;
+    }
+    if let Some(x) = opt.tick_rate {
+        config.lobby.tick_rate = x;
+    }
+    if let Some(x) = opt.catcher_interval {
+        config.game.catcher_interval = x;
+    }
+    if let Some(x) = opt.rounds {
+        config.game.rounds = x;
+    }
+    config.lobby.link = link_conditions(opt, config.lobby.link);
+    if opt.no_compression {
+        config.lobby.compression = false;
+    }
+    config
+}
+
+fn join_config(opt: &Opt) -> connection::JoinConfig {
+    connection::JoinConfig {
+        join_timeout: Duration::from_secs(opt.join_timeout),
+        join_retries: opt.join_retries,
+        start_timeout: Duration::from_secs(opt.start_timeout),
+        local_players: opt.local_players,
+        link: link_conditions(opt, Default::default()),
+        compression: !opt.no_compression,
+    }
+}
+
+fn bot_config(opt: &Opt, lobby: &server::LobbyConfig) -> bot::BotConfig {
+    bot::BotConfig {
+        tick_rate: lobby.tick_rate,
+        // one player per bot, whatever --local-players says
+        join: connection::JoinConfig { local_players: 1, ..join_config(opt) },
+    }
+}
+
+// where a server bound with this config is reachable from this machine
+fn local_addr(lobby: &server::LobbyConfig) -> String {
+    let port = lobby.bind.parse::<SocketAddr>().map(|a| a.port()).unwrap_or(20016);
+    format!("127.0.0.1:{}", port)
+}
+
+#[cfg(feature = "graphics")]
+fn client_config(opt: &Opt) -> client::ClientConfig {
+    client::ClientConfig {
+        rejoin: opt.rejoin,
+        join: join_config(opt),
+    }
 }
 
 
 fn main() {
     let opt = Opt::from_args();
+    let config = server_config(&opt);
 
-    let gp_host = litio_host::Host::new();
+    let gp_host = litio_host::Host::new(config.game);
+
+    if let Some(n) = opt.bot {
+        let addr = opt.connect.clone().unwrap_or_else(|| local_addr(&config.lobby));
+        bot::start(litio_bot::Bot::new, addr, n, bot_config(&opt, &config.lobby));
+    } else if opt.serve.is_some() || opt.bind.is_some() {
+        exit_on_error(server::serve(config.lobby, gp_host));
+    } else {
+        play(&opt, config.lobby, gp_host);
+    }
+}
+
+#[cfg(feature = "graphics")]
+fn play(opt: &Opt, lobby: server::LobbyConfig, gp_host: litio_host::Host) {
+    let client_config = client_config(opt);
     let gp_client = litio_client::Client::new();
 
-    if opt.serve.is_some() {
-        server::serve(opt.serve.unwrap(), gp_host);
-    } else if opt.connect.is_some() {
-        client::connect(opt.connect.unwrap(), gp_client);
+    if let Some(addr) = &opt.connect {
+        exit_on_error(client::start(gp_client, addr.clone(), true, client_config, None));
     } else {
-        std::thread::spawn(|| {
-            server::serve(20016, gp_host);
+        // single player, the server runs in here and no socket is bound,
+        // so nobody else can join
+        let (server_ch, client_ch) = com::Channel::loopback();
+        let lobby = server::LobbyConfig { min_players: 1, ..lobby };
+        let server_ch = server_ch.conditioned(&lobby.link);
+        let client_ch = client_ch.conditioned(&client_config.join.link);
+        std::thread::spawn(move || {
+            if let Err(e) = server::serve_on(server_ch, lobby, gp_host) {
+                println!("[s] {}", e);
+            }
         });
-        client::connect("127.0.0.1:20016".to_string(), gp_client);
+        exit_on_error(client::start(gp_client, client::LOCAL_HOST.to_string(), false, client_config, Some(client_ch)));
+    }
+}
+
+#[cfg(not(feature = "graphics"))]
+fn play(opt: &Opt, lobby: server::LobbyConfig, gp_host: litio_host::Host) {
+    if opt.connect.is_some() {
+        eprintln!("built without graphics, this binary can only serve");
+        std::process::exit(1);
+    }
+    exit_on_error(server::serve(lobby, gp_host));
+}
+
+fn exit_on_error(result: Result<(), com::ComError>) {
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
     }
 }
@@ -0,0 +1,217 @@
+use nalgebra::geometry::{Isometry3, Quaternion, Translation3, UnitQuaternion};
+use serde_derive::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+// the widest the packed components can get
+const MAX_POSITION_BITS: u32 = 16;
+const MAX_ROTATION_BITS: u32 = 10;
+
+// Bits kept per component of the transforms sent over the network, more
+// bits make smaller steps but not bigger packets.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Precision {
+    pub position_bits: u32,
+    pub rotation_bits: u32,
+}
+
+impl Default for Precision {
+    fn default() -> Self {
+        Precision {
+            position_bits: 16,
+            rotation_bits: 10,
+        }
+    }
+}
+
+// A transform in 10 bytes instead of 28: the position in fixed point
+// within the bounds, the rotation as the three smallest components of its
+// quaternion.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PackedIso {
+    pos: [u16; 3],
+    // index of the dropped component in the top two bits, then the other
+    // three in 10 bits each
+    rot: u32,
+}
+
+// How transforms are packed, both ends need the same one.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Quantizer {
+    min: [f32; 3],
+    max: [f32; 3],
+    position_bits: u32,
+    rotation_bits: u32,
+}
+
+impl Quantizer {
+    // positions out of bounds are clamped to the nearest face
+    pub fn new(min: [f32; 3], max: [f32; 3], precision: &Precision) -> Quantizer {
+        Quantizer {
+            min,
+            max,
+            position_bits: precision.position_bits.max(1).min(MAX_POSITION_BITS),
+            rotation_bits: precision.rotation_bits.max(1).min(MAX_ROTATION_BITS),
+        }
+    }
+
+    pub fn pack(&self, iso: &Isometry3<f32>) -> PackedIso {
+        let t = iso.translation.vector;
+        let mut pos = [0; 3];
+        for i in 0..3 {
+            let x = (t[i] - self.min[i]) / (self.max[i] - self.min[i]).max(std::f32::EPSILON);
+            pos[i] = quantize(x, self.position_bits) as u16;
+        }
+        PackedIso { pos, rot: self.pack_rotation(&iso.rotation) }
+    }
+
+    pub fn unpack(&self, packed: &PackedIso) -> Isometry3<f32> {
+        let mut t = [0.0; 3];
+        for i in 0..3 {
+            let x = dequantize(packed.pos[i] as u32, self.position_bits);
+            t[i] = self.min[i] + x * (self.max[i] - self.min[i]);
+        }
+        Isometry3::from_parts(
+            Translation3::new(t[0], t[1], t[2]),
+            self.unpack_rotation(packed.rot),
+        )
+    }
+
+    fn pack_rotation(&self, rot: &UnitQuaternion<f32>) -> u32 {
+        let mut c = rot.coords;
+        if c.iter().any(|x| !x.is_finite()) {
+            // a body the physics blew up, better than garbage
+            c = UnitQuaternion::identity().coords;
+        }
+        let largest = (0..4)
+            .max_by(|a, b| c[*a].abs().partial_cmp(&c[*b].abs()).unwrap_or(Ordering::Equal))
+            .unwrap();
+        // q and -q are the same rotation, keep the dropped one positive so
+        // its sign does not need sending
+        if c[largest] < 0.0 {
+            c = -c;
+        }
+        let mut packed = (largest as u32) << 30;
+        let mut shift = 20;
+        for i in (0..4).filter(|i| *i != largest) {
+            // the smaller three are within +-1/sqrt(2)
+            let x = (c[i] * std::f32::consts::SQRT_2 + 1.0) / 2.0;
+            packed|= quantize(x, self.rotation_bits) << shift;
+            shift-= 10;
+        }
+        packed
+    }
+
+    fn unpack_rotation(&self, packed: u32) -> UnitQuaternion<f32> {
+        let largest = (packed >> 30) as usize;
+        let mut c = [0.0; 4];
+        let mut shift = 20;
+        let mut sum = 0.0;
+        for i in (0..4).filter(|i| *i != largest) {
+            let x = dequantize((packed >> shift) & 0x3ff, self.rotation_bits);
+            c[i] = (x * 2.0 - 1.0) * std::f32::consts::FRAC_1_SQRT_2;
+            sum+= c[i] * c[i];
+            shift-= 10;
+        }
+        c[largest] = (1.0 - sum).max(0.0).sqrt();
+        // coords are i, j, k, w
+        UnitQuaternion::new_normalize(Quaternion::new(c[3], c[0], c[1], c[2]))
+    }
+}
+
+// x from 0 to 1, clamped, to an integer of `bits` bits
+fn quantize(x: f32, bits: u32) -> u32 {
+    let steps = ((1 << bits) - 1) as f32;
+    (x.max(0.0).min(1.0) * steps).round() as u32
+}
+
+fn dequantize(q: u32, bits: u32) -> f32 {
+    let steps = ((1 << bits) - 1) as f32;
+    q.min((1 << bits) - 1) as f32 / steps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::litio_host::LitioUpdate;
+    use nalgebra::base::Vector3;
+    use std::collections::HashMap;
+
+    fn quantizer() -> Quantizer {
+        Quantizer::new([-100.0, -100.0, -100.0], [100.0, 160.0, 100.0], &Precision::default())
+    }
+
+    fn at(x: f32, y: f32, z: f32) -> Isometry3<f32> {
+        Isometry3::from_parts(Translation3::new(x, y, z), UnitQuaternion::identity())
+    }
+
+    // how far apart two positions may be after a round trip
+    fn step(q: &Quantizer) -> f32 {
+        (0..3).map(|i| q.max[i] - q.min[i]).fold(0.0, f32::max) / ((1 << q.position_bits) - 1) as f32
+    }
+
+    #[test]
+    fn positions_round_trip_within_a_step() {
+        let q = quantizer();
+        for iso in &[at(0.0, 0.0, 0.0), at(12.34, 56.78, -9.87), at(-100.0, -100.0, -100.0), at(100.0, 160.0, 100.0)] {
+            let back = q.unpack(&q.pack(iso));
+            assert!((back.translation.vector - iso.translation.vector).norm() <= step(&q), "{:?} -> {:?}", iso, back);
+        }
+    }
+
+    #[test]
+    fn positions_out_of_bounds_are_clamped() {
+        let q = quantizer();
+        let back = q.unpack(&q.pack(&at(-500.0, 1000.0, 250.0)));
+        let expected = Vector3::new(-100.0, 160.0, 100.0);
+        assert!((back.translation.vector - expected).norm() <= step(&q), "{:?}", back);
+    }
+
+    #[test]
+    fn rotations_round_trip_whichever_component_is_largest() {
+        let q = quantizer();
+        for largest in 0..4 {
+            for sign in &[1.0, -1.0] {
+                // coords are i, j, k, w
+                let mut c = [0.2, -0.3, 0.1, 0.25];
+                c[largest] = 0.9 * sign;
+                let rot = UnitQuaternion::new_normalize(Quaternion::new(c[3], c[0], c[1], c[2]));
+                let iso = Isometry3::from_parts(Translation3::new(1.0, 2.0, 3.0), rot);
+
+                let packed = q.pack(&iso);
+                assert_eq!((packed.rot >> 30) as usize, largest);
+                let back = q.unpack(&packed);
+                assert!(back.rotation.angle_to(&rot) < 0.01, "{:?} -> {:?}", rot, back.rotation);
+            }
+        }
+    }
+
+    #[test]
+    fn broken_rotations_pack_as_identity() {
+        let q = quantizer();
+        let nan = UnitQuaternion::new_unchecked(Quaternion::new(std::f32::NAN, 0.0, 0.0, 0.0));
+        let iso = Isometry3::from_parts(Translation3::new(1.0, 2.0, 3.0), nan);
+        let back = q.unpack(&q.pack(&iso));
+        assert!(back.rotation.angle_to(&UnitQuaternion::identity()) < 0.01, "{:?}", back.rotation);
+    }
+
+    #[test]
+    fn packed_snapshots_are_smaller() {
+        let q = quantizer();
+        let isos: HashMap<usize, Isometry3<f32>> = (0..120)
+            .map(|i| {
+                let rot = UnitQuaternion::from_euler_angles(i as f32 * 0.1, i as f32 * 0.2, i as f32 * 0.3);
+                (i, Isometry3::from_parts(Translation3::new(i as f32 * 0.5, i as f32, -(i as f32)), rot))
+            })
+            .collect();
+        let update = LitioUpdate {
+            transforms: isos.iter().map(|(id, iso)| (*id, q.pack(iso))).collect(),
+            catcher: 0,
+        };
+
+        let packed = bincode::serialized_size(&update).unwrap();
+        let raw = bincode::serialized_size(&(&isos, 0usize)).unwrap();
+        // 10 bytes per transform instead of at least 28, the ids stay as they are
+        assert!(raw >= packed + 18 * isos.len() as u64, "{} bytes packed, {} raw", packed, raw);
+    }
+}
@@ -1,9 +1,11 @@
-use crate::com::Channel;
+use crate::com::{Channel, ComError, Event};
+use crate::link::LinkConditions;
 use serde_derive::{Deserialize, Serialize};
 use std::collections::HashMap;
 use crate::util;
-use crate::gameplay::GameplayHost;
-use std::net::SocketAddr;
+use crate::gameplay::{GameplayHost, GameStatus};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
 
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -13,34 +15,152 @@ pub struct Player {
     pub id: usize,
     pub addr: SocketAddr,
     pub color: (f32, f32, f32),
+    pub name: String,
+    pub ready: bool,
+    // owned by the connection that can start the match
+    pub host: bool,
 }
 
 impl Player {
-    pub fn new(id: usize, addr: SocketAddr, color: (f32, f32, f32)) -> Player {
+    pub fn new(id: usize, addr: SocketAddr, color: (f32, f32, f32), name: String) -> Player {
         Player {
             id,
             addr,
             color,
+            name,
+            ready: false,
+            host: false,
         }
     }
 }
 
 
+// Bump whenever Packet, a gameplay message or one of their payloads changes
+// shape: bincode carries no schema, so mismatched peers read garbage.
+pub const PROTOCOL_VERSION: u32 = 11;
+
+pub const MAX_NAME_LEN: usize = 16;
+pub const MAX_LOCAL_PLAYERS: usize = 4;
+
+// Lobby packets share the channel with the gameplay messages: this prefix
+// tells them apart, gameplay enums start with a small variant tag instead.
+const LOBBY_MAGIC: u32 = 0x6775_7069;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JoinRequest {
+    // must stay the first field, see Lobby::on_raw_packet
+    pub version: u32,
+    pub gameplay: String,
+    pub color: (f32, f32, f32),
+    pub name: String,
+    // players sharing this connection, e.g. split-screen
+    pub local_players: usize,
+    // token of a previous session to reclaim
+    pub session: Option<u64>,
+    // whether we want compressed packets, see Channel::set_compression
+    pub compression: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RejectReason {
+    LobbyFull,
+    GameInProgress,
+    VersionMismatch,
+    UnknownGameplay,
+    Banned,
+    UnknownSession,
+}
+
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let reason = match self {
+            RejectReason::LobbyFull => "the lobby is full",
+            RejectReason::GameInProgress => "a game is in progress",
+            RejectReason::VersionMismatch => "client and server versions differ",
+            RejectReason::UnknownGameplay => "the server runs a different game",
+            RejectReason::Banned => "you are banned from this server",
+            RejectReason::UnknownSession => "the session is unknown or expired",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Packet {
-    Join((f32, f32, f32)),
-    Joined(usize),
+    // must stay the first variant, see Lobby::on_raw_packet
+    Join(JoinRequest),
+    // ids of the players owned by the connection, session token, whether
+    // both ends compress from now on
+    Joined(Vec<usize>, u64, bool),
+    Rejected(RejectReason),
     GameStarted,
     Control,
+    KeepAlive,
+    Ready,
+    Unready,
+    Roster(Vec<Player>),
+    StartMatch,
+    // seconds left before the match starts
+    Countdown(u32),
+    CountdownAborted,
+    // final (player id, score) pairs, best first; the lobby is pending again
+    GameOver(Vec<(usize, i32)>),
 }
 
-#[allow(dead_code)]
+impl Packet {
+    pub fn send_to(self, ch: &mut Channel, addr: SocketAddr) -> Result<(), ComError> {
+        ch.send_ro(addr, (LOBBY_MAGIC, self))
+    }
+
+    // None if the payload is not a lobby packet (i.e. a gameplay message)
+    pub fn decode(data: &[u8]) -> Option<Packet> {
+        match bincode::deserialize::<(u32, Packet)>(data) {
+            Ok((LOBBY_MAGIC, packet)) => Some(packet),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
 pub struct LobbyConfig {
-    min_players: usize,
-    port: u16,
+    pub bind: String,
+    pub min_players: usize,
+    pub max_players: usize,
+    pub tick_rate: u64,
+    // accept new players after the match started
+    pub late_join: bool,
+    pub banned: Vec<IpAddr>,
+    // can start the match like the host does
+    pub admins: Vec<IpAddr>,
+    // seconds between everybody being ready and the match start
+    pub countdown: u64,
+    // degrades what the server receives, see link::Conditioned
+    pub link: LinkConditions,
+    // grant compression to the clients that ask for it
+    pub compression: bool,
+}
+
+impl Default for LobbyConfig {
+    fn default() -> Self {
+        LobbyConfig {
+            bind: "0.0.0.0:20016".to_string(),
+            min_players: 2,
+            max_players: 16,
+            tick_rate: 60,
+            late_join: true,
+            banned: vec![],
+            admins: vec![],
+            countdown: 3,
+            link: LinkConditions::default(),
+            compression: true,
+        }
+    }
 }
 
 #[allow(dead_code)]
+#[derive(PartialEq)]
 pub enum LobbyState {
     Setup,
     Pending,
@@ -52,6 +172,15 @@ pub enum LobbyState {
 struct Lobby<G> {
     players: HashMap<usize, Player>,
     sock2pl: HashMap<SocketAddr, usize>,
+    // session token -> ids of the players owned by that connection
+    sessions: HashMap<u64, Vec<usize>>,
+    departed: HashMap<usize, Player>,
+    // ids are never reused, departed players may come back
+    next_id: usize,
+    // when the pending match starts, and whether the host forced it
+    start_at: Option<Instant>,
+    forced_start: bool,
+    announced: u64,
     state: LobbyState,
     config: LobbyConfig,
     ch: Channel,
@@ -61,55 +190,375 @@ struct Lobby<G> {
 
 impl<G> Lobby<G>
 where G: GameplayHost {
-    fn spawn(config: LobbyConfig, gameplay: G) -> Lobby<G> {
+    fn spawn(ch: Channel, config: LobbyConfig, gameplay: G) -> Lobby<G> {
 
         Lobby {
             players: HashMap::new(),
             sock2pl: HashMap::new(),
+            sessions: HashMap::new(),
+            departed: HashMap::new(),
+            next_id: 1,
+            start_at: None,
+            forced_start: false,
+            announced: 0,
             state: LobbyState::Setup,
-            ch: Channel::spawn(Some(format!("0.0.0.0:{}", config.port))),
+            ch,
             config,
             gameplay,
         }
     }
 
-    fn wait_players(&mut self) {
+    fn wait_players(&mut self) -> Result<(), ComError> {
         self.state = LobbyState::Pending;
-        while self.players.len() < self.config.min_players {
-            println!("[s] waiting players");
-            let (addr, data) = self.ch.recv().unwrap();
-            match data {
-                Packet::Join(color) => {
-                    println!("[s] new client {} {:?}!!", addr, color);
-                    let id = self.players.len()+1;
-                    self.players.insert(id, Player::new(id, addr, color));
-                    self.players.insert(id+1, Player::new(id+1, addr, color));
-                    self.sock2pl.insert(addr, id);
-                    self.broadcast(Packet::Joined(id));
-                },
+        println!("[s] waiting players");
+        loop {
+            match self.ch.recv_raw_timeout(Duration::from_millis(100))? {
+                Some(Event::Packet(addr, data)) => self.on_raw_packet(addr, &data),
+                Some(Event::TimedOut(addr)) | Some(Event::Disconnected(addr)) => self.on_disconnect(addr),
                 _ => {},
             }
+            if self.countdown_over() {
+                return Ok(())
+            }
+        }
+    }
+
+    fn start_countdown(&mut self, forced: bool) {
+        self.forced_start |= forced;
+        if self.start_at.is_some() {
+            return
+        }
+        println!("[s] match starts in {}s", self.config.countdown);
+        self.start_at = Some(Instant::now() + Duration::from_secs(self.config.countdown));
+        self.announced = 0;
+    }
+
+    fn abort_countdown(&mut self, why: &str) {
+        self.forced_start = false;
+        if self.start_at.take().is_some() {
+            println!("[s] countdown aborted: {}", why);
+            self.broadcast(Packet::CountdownAborted);
+        }
+    }
+
+    // Announces every second left, true once the match should start.
+    fn countdown_over(&mut self) -> bool {
+        let start_at = match self.start_at {
+            Some(start_at) => start_at,
+            None => return false,
+        };
+        if self.players.len() < self.config.min_players {
+            self.abort_countdown("not enough players");
+            return false
+        }
+
+        let now = Instant::now();
+        if now >= start_at {
+            self.start_at = None;
+            self.forced_start = false;
+            return true
+        }
+        let left = ((start_at - now).as_millis() as u64 + 999) / 1000;
+        if left != self.announced {
+            self.announced = left;
+            self.broadcast(Packet::Countdown(left as u32));
+        }
+        false
+    }
+
+    fn host_addr(&self) -> Option<SocketAddr> {
+        self.players.values().min_by_key(|pl| pl.id).map(|pl| pl.addr)
+    }
+
+    fn on_raw_packet(&mut self, addr: SocketAddr, data: &[u8]) {
+        match Packet::decode(data) {
+            Some(packet) => self.on_lobby_packet(addr, packet),
+            // The layout of a Join from another version may differ, but it
+            // still starts with the prefix, the variant tag (0) and the
+            // protocol version.
+            None => match bincode::deserialize::<(u32, u32, u32)>(data) {
+                Ok((LOBBY_MAGIC, 0, version)) if version != PROTOCOL_VERSION => {
+                    println!("[s] client {} speaks protocol {}, we speak {}", addr, version, PROTOCOL_VERSION);
+                    self.reject(addr, RejectReason::VersionMismatch);
+                },
+                _ => println!("[s] invalid packet from {}", addr),
+            },
+        }
+    }
+
+    fn on_lobby_packet(&mut self, addr: SocketAddr, packet: Packet) {
+        if self.config.banned.contains(&addr.ip()) {
+            self.reject(addr, RejectReason::Banned);
+            return
+        }
+        match packet {
+            Packet::Join(req) => {
+                if req.version != PROTOCOL_VERSION {
+                    self.reject(addr, RejectReason::VersionMismatch);
+                } else if req.gameplay != self.gameplay.gameplay_id() {
+                    self.reject(addr, RejectReason::UnknownGameplay);
+                } else if let Some(id) = self.sock2pl.get(&addr).cloned() {
+                    // a retried join, our answer got lost
+                    self.resend_joined(addr, id, req.compression);
+                } else if let Some(token) = req.session {
+                    self.rejoin(addr, token, req.compression);
+                } else {
+                    match self.state {
+                        LobbyState::Pending => self.join(addr, &req),
+                        LobbyState::Gameplay if self.config.late_join => self.join(addr, &req),
+                        _ => self.reject(addr, RejectReason::GameInProgress),
+                    }
+                }
+            },
+            Packet::KeepAlive => {
+                if self.sock2pl.contains_key(&addr) {
+                    self.send(addr, Packet::KeepAlive);
+                }
+            },
+            Packet::Ready => self.set_ready(addr, true),
+            Packet::Unready => self.set_ready(addr, false),
+            Packet::StartMatch => self.start_match(addr),
+            _ => {},
+        }
+    }
+
+    fn set_ready(&mut self, addr: SocketAddr, ready: bool) {
+        if self.state != LobbyState::Pending {
+            return
+        }
+        self.players.values_mut()
+            .filter(|pl| pl.addr == addr)
+            .for_each(|pl| pl.ready = ready);
+        self.broadcast_roster();
+        self.check_ready();
+    }
+
+    fn check_ready(&mut self) {
+        let all_ready = !self.players.is_empty() && self.players.values().all(|pl| pl.ready);
+        if all_ready && self.players.len() >= self.config.min_players {
+            self.start_countdown(false);
+        } else if !all_ready && !self.forced_start {
+            self.abort_countdown("not everybody is ready");
+        }
+    }
+
+    fn start_match(&mut self, addr: SocketAddr) {
+        if self.state != LobbyState::Pending {
+            return
+        }
+        if self.host_addr() != Some(addr) && !self.config.admins.contains(&addr.ip()) {
+            println!("[s] {} is not allowed to start the match", addr);
+            return
+        }
+        if self.players.len() < self.config.min_players {
+            println!("[s] cannot start, {} of {} players", self.players.len(), self.config.min_players);
+            return
+        }
+        self.start_countdown(true);
+    }
+
+    // Lets a connection in, compressed if both ends want it.
+    fn send_joined(&mut self, addr: SocketAddr, ids: Vec<usize>, token: u64, compression: bool) {
+        let compression = compression && self.config.compression;
+        self.send(addr, Packet::Joined(ids, token, compression));
+        self.ch.set_compression(addr, compression);
+    }
+
+    fn resend_joined(&mut self, addr: SocketAddr, id: usize, compression: bool) {
+        let (token, ids) = self.sessions.iter()
+            .find(|(_, ids)| ids.contains(&id))
+            .map(|(token, ids)| (*token, ids.clone()))
+            .expect("player without session");
+        self.send_joined(addr, ids, token, compression);
+        if let LobbyState::Gameplay = self.state {
+            self.send(addr, Packet::GameStarted);
+        }
+    }
+
+    fn reject(&mut self, addr: SocketAddr, reason: RejectReason) {
+        println!("[s] rejecting {}: {}", addr, reason);
+        self.send(addr, Packet::Rejected(reason));
+    }
+
+    // A single connection may own more than one player. Ids are allocated
+    // in order, so sorting them gives the local order of the client.
+    fn ids_of(&self, addr: SocketAddr) -> Vec<usize> {
+        let mut ids: Vec<usize> = self.players.values()
+            .filter(|pl| pl.addr == addr)
+            .map(|pl| pl.id)
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    fn alloc_id(&mut self) -> usize {
+        let id = self.next_id;
+        self.next_id+= 1;
+        id
+    }
+
+    fn join(&mut self, addr: SocketAddr, req: &JoinRequest) {
+        let local_players = req.local_players.max(1).min(MAX_LOCAL_PLAYERS);
+        if self.players.len() + local_players > self.config.max_players {
+            self.reject(addr, RejectReason::LobbyFull);
+            return
+        }
+        println!("[s] new client {} {} {:?} with {} players", addr, req.name, req.color, local_players);
+        let ids: Vec<usize> = (0..local_players).map(|_| self.alloc_id()).collect();
+        for id in &ids {
+            let name = self.unique_name(&req.name);
+            self.players.insert(*id, Player::new(*id, addr, req.color, name));
+        }
+        self.sock2pl.insert(addr, ids[0]);
+
+        let token = util::token();
+        self.sessions.insert(token, ids.clone());
+        self.send_joined(addr, ids.clone(), token, req.compression);
+        self.broadcast_roster();
+        if self.state == LobbyState::Pending {
+            self.check_ready();
+        }
+
+        if let LobbyState::Gameplay = self.state {
+            println!("[s] late join of {}", addr);
+            self.send(addr, Packet::GameStarted);
+            for id in &ids {
+                let pl = self.players[id].clone();
+                self.gameplay.on_player_joined(&mut self.ch, &pl);
+            }
         }
     }
 
-    fn run_gameplay(&mut self) {
+    // Printable, at most MAX_NAME_LEN chars and not taken by another player,
+    // present or departed (they may rejoin).
+    fn unique_name(&self, wanted: &str) -> String {
+        let name: String = wanted.chars()
+            .filter(|c| !c.is_control())
+            .collect();
+        let name = match name.trim() {
+            "" => "player",
+            name => name,
+        };
+        // cut the name short enough for the suffix to fit
+        let fit = |suffix: &str| {
+            let room = MAX_NAME_LEN - suffix.chars().count();
+            let name: String = name.chars().take(room).collect();
+            format!("{}{}", name.trim_end(), suffix)
+        };
+        let taken = |name: &str| self.players.values()
+            .chain(self.departed.values())
+            .any(|pl| pl.name == name);
+        let first = fit("");
+        if !taken(&first) {
+            return first
+        }
+        (2..).map(|n| fit(&format!(" ({})", n)))
+            .find(|name| !taken(name))
+            .unwrap()
+    }
+
+    fn rejoin(&mut self, addr: SocketAddr, token: u64, compression: bool) {
+        let ids = match self.sessions.get(&token) {
+            Some(ids) => ids.clone(),
+            None => {
+                self.reject(addr, RejectReason::UnknownSession);
+                return
+            },
+        };
+        // the slots of the departed players may have been taken meanwhile
+        let others = self.players.keys().filter(|id| !ids.contains(id)).count();
+        if others + ids.len() > self.config.max_players {
+            self.reject(addr, RejectReason::LobbyFull);
+            return
+        }
+
+        // the previous connection may not have timed out yet
+        let mut stale: Vec<SocketAddr> = ids.iter()
+            .filter_map(|id| self.players.get(id))
+            .map(|pl| pl.addr)
+            .collect();
+        stale.dedup();
+        stale.into_iter().for_each(|old| self.on_disconnect(old));
+
+        println!("[s] client {} rejoined as {:?}", addr, ids);
+        self.sock2pl.insert(addr, ids[0]);
+        self.send_joined(addr, ids.clone(), token, compression);
+        if let LobbyState::Gameplay = self.state {
+            self.send(addr, Packet::GameStarted);
+        }
+
+        for id in &ids {
+            if let Some(mut pl) = self.departed.remove(id) {
+                pl.addr = addr;
+                self.players.insert(*id, pl.clone());
+                if let LobbyState::Gameplay = self.state {
+                    self.gameplay.on_player_rejoined(&mut self.ch, &pl);
+                }
+            }
+        }
+        self.broadcast_roster();
+    }
+
+    fn on_disconnect(&mut self, addr: SocketAddr) {
+        if self.sock2pl.remove(&addr).is_none() {
+            println!("[s] unknown client {} left", addr);
+            return
+        }
+        self.ch.set_compression(addr, false);
+
+        for id in self.ids_of(addr) {
+            println!("[s] player {} left ({})", id, addr);
+            if let Some(pl) = self.players.remove(&id) {
+                self.departed.insert(id, pl);
+            }
+            if let LobbyState::Gameplay = self.state {
+                self.gameplay.on_player_left(&mut self.ch, id);
+            }
+        }
+        self.broadcast_roster();
+        if self.state == LobbyState::Pending {
+            self.check_ready();
+        }
+    }
+
+    // Runs the match until the gameplay reports it is over.
+    fn run_gameplay(&mut self) -> Result<(), ComError> {
         self.state = LobbyState::Gameplay;
         println!("[s] game started");
+        self.players.values_mut().for_each(|pl| pl.ready = false);
         self.gameplay.init(&mut self.ch, &self.players);
         self.broadcast(Packet::GameStarted);
 
-        let interval_ms = 1000/60;
+        let interval_ms = 1000 / self.config.tick_rate.max(1);
         loop {
             let t_start = util::now();
 
-            let p = self.ch.recv_all();
-            p.iter().for_each(|packet| {
-                self.gameplay.on_packet(&mut self.ch, self.sock2pl[&packet.0], &packet.1);
-            });
-
+            for event in self.ch.recv_all()? {
+                match event {
+                    Event::Packet(addr, data) => match self.sock2pl.get(&addr).cloned() {
+                        Some(_) => match Packet::decode(&data) {
+                            Some(packet) => self.on_lobby_packet(addr, packet),
+                            None => {
+                                let senders = self.ids_of(addr);
+                                self.gameplay.on_packet(&mut self.ch, &senders, &data);
+                            },
+                        },
+                        None => self.on_raw_packet(addr, &data),
+                    },
+                    Event::TimedOut(addr) | Event::Disconnected(addr) => self.on_disconnect(addr),
+                    Event::Connected(_) => {},
+                }
+            }
 
-            self.gameplay.update(&mut self.ch, &self.players);
 
+            if let GameStatus::Over(results) = self.gameplay.update(&mut self.ch, &self.players) {
+                self.state = LobbyState::Finished;
+                println!("[s] game over: {:?}", results);
+                println!("[s] channel: {}", self.ch.stats());
+                self.broadcast(Packet::GameOver(results));
+                self.broadcast_roster();
+                return Ok(())
+            }
             let t_used = ((util::now() - t_start) * 1000.0) as u64;
             // println!("[s] update took {} ms", t_used);
             if t_used < interval_ms {
@@ -118,21 +567,123 @@ where G: GameplayHost {
         }
     }
 
+    // Sends only fail once the socket is gone, and the next receive reports
+    // that and ends the lobby, so they are just logged.
+    fn send(&mut self, addr: SocketAddr, packet: Packet) {
+        if let Err(e) = packet.send_to(&mut self.ch, addr) {
+            println!("[s] cannot send to {}: {}", addr, e);
+        }
+    }
+
     #[allow(dead_code)]
-    fn broadcast<T: serde::Serialize>(&mut self, data: T) {
+    fn broadcast(&mut self, packet: Packet) {
         let ch = &mut self.ch;
-        self.players.values().for_each(|pl| {
-            ch.send_rs(pl.addr.clone(), &data);
+        self.sock2pl.keys().for_each(|addr| {
+            if let Err(e) = ch.send_ro(*addr, (LOBBY_MAGIC, &packet)) {
+                println!("[s] cannot send to {}: {}", addr, e);
+            }
         });
     }
+
+    fn broadcast_roster(&mut self) {
+        let host = self.host_addr();
+        let mut roster: Vec<Player> = self.players.values().cloned().collect();
+        roster.iter_mut().for_each(|pl| pl.host = Some(pl.addr) == host);
+        roster.sort_by_key(|pl| pl.id);
+        self.broadcast(Packet::Roster(roster));
+    }
 }
 
-pub fn serve<G>(port: u16, gameplay: G)
+// Only returns if the socket cannot be opened or dies.
+pub fn serve<G>(config: LobbyConfig, gameplay: G) -> Result<(), ComError>
 where G: GameplayHost {
-    let mut lobby = Lobby::spawn(LobbyConfig {
-        port,
-        min_players: 2,
-    }, gameplay);
-    lobby.wait_players();
-    lobby.run_gameplay();
+    println!("[s] serving on {}", config.bind);
+    let ch = Channel::spawn(Some(config.bind.clone()))?.conditioned(&config.link);
+    serve_on(ch, config, gameplay)
+}
+
+// Like serve, over a channel of our choice (e.g. Channel::loopback); the
+// bind address of the config is ignored.
+pub fn serve_on<G>(ch: Channel, config: LobbyConfig, gameplay: G) -> Result<(), ComError>
+where G: GameplayHost {
+    let mut lobby = Lobby::spawn(ch, config, gameplay);
+    loop {
+        lobby.wait_players()?;
+        lobby.run_gameplay()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::com::loopback_addrs;
+    use crossbeam_channel::Sender;
+
+    // Reports the players that left, plays forever otherwise.
+    struct Stub {
+        left: Sender<usize>,
+    }
+
+    impl GameplayHost for Stub {
+        fn gameplay_id(&self) -> &'static str { "stub" }
+        fn init(&mut self, _ch: &mut Channel, _players: &HashMap::<usize, Player>) {}
+        fn update(&mut self, _ch: &mut Channel, _players: &HashMap::<usize, Player>) -> GameStatus { GameStatus::Running }
+        fn on_packet(&mut self, _ch: &mut Channel, _senders: &[usize], _tx: &[u8]) {}
+        fn on_player_joined(&mut self, _ch: &mut Channel, _player: &Player) {}
+        fn on_player_left(&mut self, _ch: &mut Channel, id: usize) {
+            self.left.send(id).ok();
+        }
+        fn on_player_rejoined(&mut self, _ch: &mut Channel, _player: &Player) {}
+    }
+
+    // The next lobby packet from the server, skipping rosters and the like
+    // until `want` matches.
+    fn expect<F>(ch: &mut Channel, want: F) -> Packet
+    where F: Fn(&Packet) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+            if let Some(Event::Packet(_, data)) = ch.recv_raw_timeout(timeout).unwrap() {
+                match Packet::decode(&data) {
+                    Some(packet) if want(&packet) => return packet,
+                    _ => {},
+                }
+            }
+        }
+        panic!("the server did not answer in time")
+    }
+
+    #[test]
+    fn players_join_play_and_leave_over_loopback() {
+        let (server_ch, mut ch) = Channel::loopback();
+        let (server, _) = loopback_addrs();
+        let (left_tx, left_rx) = crossbeam_channel::unbounded();
+        let config = LobbyConfig {
+            min_players: 1,
+            countdown: 0,
+            ..LobbyConfig::default()
+        };
+        // ends once our end of the loopback is dropped
+        std::thread::spawn(move || serve_on(server_ch, config, Stub { left: left_tx }));
+
+        Packet::Join(JoinRequest {
+            version: PROTOCOL_VERSION,
+            gameplay: "stub".to_string(),
+            color: (1.0, 0.0, 0.0),
+            name: "alice".to_string(),
+            local_players: 1,
+            session: None,
+            compression: false,
+        }).send_to(&mut ch, server).unwrap();
+        let ids = match expect(&mut ch, |p| match p { Packet::Joined(..) => true, _ => false }) {
+            Packet::Joined(ids, _, _) => ids,
+            _ => unreachable!(),
+        };
+        assert_eq!(ids.len(), 1);
+
+        Packet::Ready.send_to(&mut ch, server).unwrap();
+        expect(&mut ch, |p| match p { Packet::GameStarted => true, _ => false });
+
+        ch.disconnect(server).unwrap();
+        assert_eq!(left_rx.recv_timeout(Duration::from_secs(5)), Ok(ids[0]));
+    }
 }
@@ -0,0 +1,112 @@
+use kiss3d::camera::{Camera, FirstPerson};
+use kiss3d::context::Context;
+use kiss3d::event::WindowEvent;
+use kiss3d::resource::ShaderUniform;
+use kiss3d::window::Canvas;
+use na::{Isometry3, Matrix4, Perspective3, Point3};
+
+// Renders the scene once per local player, each pass in its own viewport:
+// side by side for two players, a 2x2 grid for three or four.
+pub struct SplitCamera {
+    cams: Vec<FirstPerson>,
+    fov: f32,
+    znear: f32,
+    zfar: f32,
+    // framebuffer size, refreshed every frame
+    size: (u32, u32),
+}
+
+impl SplitCamera {
+    pub fn new(fov: f32, znear: f32, zfar: f32) -> SplitCamera {
+        let mut cam = SplitCamera {
+            cams: vec![],
+            fov,
+            znear,
+            zfar,
+            size: (800, 600),
+        };
+        cam.split(1);
+        cam
+    }
+
+    pub fn split(&mut self, n: usize) {
+        let (fov, znear, zfar) = (self.fov, self.znear, self.zfar);
+        self.cams = (0..n.max(1)).map(|_| FirstPerson::new_with_frustrum(
+            fov,
+            znear,
+            zfar,
+            Point3::new(10.0, 10.0, 10.0),
+            Point3::new(0.0, 0.0, 0.0),
+        )).collect();
+    }
+
+    pub fn cams_mut(&mut self) -> &mut [FirstPerson] {
+        &mut self.cams
+    }
+
+    // x, y (from the bottom left), width and height of a pass
+    fn viewport(&self, pass: usize) -> (i32, i32, i32, i32) {
+        let n = self.cams.len();
+        let cols = if n > 1 { 2 } else { 1 };
+        let rows = (n + cols - 1) / cols;
+        let w = self.size.0 as usize / cols;
+        let h = self.size.1 as usize / rows;
+        let x = (pass % cols) * w;
+        let y = self.size.1 as usize - (pass / cols + 1) * h;
+        (x as i32, y as i32, w as i32, h as i32)
+    }
+}
+
+impl Camera for SplitCamera {
+    // the gameplay places the cameras, user input is not ours to handle
+    fn handle_event(&mut self, _canvas: &Canvas, _event: &WindowEvent) {}
+
+    fn eye(&self) -> Point3<f32> {
+        self.cams[0].eye()
+    }
+
+    fn view_transform(&self) -> Isometry3<f32> {
+        self.cams[0].view_transform()
+    }
+
+    fn transformation(&self) -> Matrix4<f32> {
+        self.cams[0].transformation()
+    }
+
+    fn inverse_transformation(&self) -> Matrix4<f32> {
+        self.cams[0].inverse_transformation()
+    }
+
+    fn clip_planes(&self) -> (f32, f32) {
+        (self.znear, self.zfar)
+    }
+
+    fn update(&mut self, canvas: &Canvas) {
+        self.size = canvas.size();
+    }
+
+    fn upload(&self, pass: usize, proj: &mut ShaderUniform<Matrix4<f32>>, view: &mut ShaderUniform<Matrix4<f32>>) {
+        let (_, _, w, h) = self.viewport(pass);
+        let aspect = w.max(1) as f32 / h.max(1) as f32;
+        proj.upload(&Perspective3::new(aspect, self.fov, self.znear, self.zfar).to_homogeneous());
+        view.upload(&self.cams[pass].view_transform().to_homogeneous());
+    }
+
+    fn num_passes(&self) -> usize {
+        self.cams.len()
+    }
+
+    fn start_pass(&self, pass: usize, _canvas: &Canvas) {
+        let (x, y, w, h) = self.viewport(pass);
+        let ctxt = Context::get();
+        ctxt.viewport(x, y, w, h);
+        ctxt.scissor(x, y, w, h);
+        ctxt.enable(Context::SCISSOR_TEST);
+    }
+
+    fn render_complete(&self, _canvas: &Canvas) {
+        let ctxt = Context::get();
+        ctxt.disable(Context::SCISSOR_TEST);
+        ctxt.viewport(0, 0, self.size.0 as i32, self.size.1 as i32);
+    }
+}
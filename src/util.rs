@@ -19,6 +19,10 @@ pub fn uid() -> usize {
     rand::thread_rng().gen_range(100000, 999999)
 }
 #[allow(dead_code)]
+pub fn token() -> u64 {
+    rand::random()
+}
+#[allow(dead_code)]
 pub fn rand_float(min: f32, max: f32) -> f32 {
     use rand::Rng;
     rand::thread_rng().gen_range(min, max)
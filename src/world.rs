@@ -141,6 +141,15 @@ impl World {
         id
     }
 
+    #[allow(dead_code)]
+    pub fn remove_entity(&mut self, id: usize) {
+        if let Some(e) = self.entities.remove(&id) {
+            self.col2id.remove(&e.collider);
+            self.colliders.remove(e.collider);
+            self.bodies.remove(e.handle);
+        }
+    }
+
     #[allow(dead_code)]
     pub fn update_physics(&mut self) {
         self.geometrical_world.sync_colliders(&self.bodies, &mut self.colliders);